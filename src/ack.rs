@@ -0,0 +1,191 @@
+use crate::shutdown::Shared;
+use std::{
+    collections::{BTreeSet, VecDeque},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, MutexGuard,
+    },
+    task::{Context, Poll, Waker},
+};
+
+/// When items sent through a `Pipeline` count as delivered for `poll_flush` and `poll_close`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AckMode {
+    /// Flushing completes immediately, without waiting on the receiver.
+    #[default]
+    None,
+    /// Items are acknowledged as the receiver yields them.
+    OnReceive,
+    /// Items are acknowledged through an `Acker` once the consumer is done with them.
+    Explicit,
+}
+
+/// Acknowledgement bookkeeping shared by a channel's pipelines and receiver.
+///
+/// Items are numbered in the order they enter the channel, and each sender waits for every item
+/// up to its last one to be settled. An item is settled when it is acknowledged, or when the
+/// channel discards it, which can happen out of order, so settled items are tracked one by one.
+#[derive(Debug)]
+pub(crate) struct Acks {
+    mode: AckMode,
+    sequence: Mutex<u64>,
+    settled: Mutex<Settled>,
+    /// The receiver plus every live `Acker`. Once this reaches zero nothing can be acknowledged
+    /// anymore, so flushes stop waiting.
+    ackers: AtomicUsize,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+#[derive(Debug, Default)]
+struct Settled {
+    /// Every item up to and including this one is settled.
+    through: u64,
+    /// Settled items past `through`, waiting for the items before them.
+    ahead: BTreeSet<u64>,
+    /// Items yielded under `AckMode::Explicit` and not acknowledged yet, oldest first.
+    unacked: VecDeque<u64>,
+}
+
+impl Settled {
+    /// Returns whether `through` moved.
+    fn settle(&mut self, sequence: u64) -> bool {
+        if sequence <= self.through {
+            return false;
+        }
+        self.ahead.insert(sequence);
+        let before = self.through;
+        while self.ahead.remove(&(self.through + 1)) {
+            self.through += 1;
+        }
+        self.through != before
+    }
+}
+
+impl Acks {
+    pub(crate) fn new(mode: AckMode) -> Self {
+        Self {
+            mode,
+            sequence: Mutex::new(0),
+            settled: Mutex::new(Settled::default()),
+            ackers: AtomicUsize::new(1),
+            wakers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Locks the sequence counter. Senders hold this across the send so numbering matches
+    /// channel order, and bump it once the item is in.
+    pub(crate) fn sequence(&self) -> Option<MutexGuard<'_, u64>> {
+        match self.mode {
+            AckMode::None => None,
+            AckMode::OnReceive | AckMode::Explicit => Some(self.sequence.lock().unwrap()),
+        }
+    }
+
+    /// Records that the receiver yielded item `sequence`, which settles it under
+    /// `AckMode::OnReceive`.
+    pub(crate) fn received(&self, sequence: u64) {
+        match self.mode {
+            AckMode::None => {}
+            AckMode::OnReceive => self.settle(sequence),
+            AckMode::Explicit => self.settled.lock().unwrap().unacked.push_back(sequence),
+        }
+    }
+
+    /// Acknowledges the `count` oldest items yielded but not yet acknowledged.
+    pub(crate) fn ack(&self, count: u64) {
+        let mut settled = self.settled.lock().unwrap();
+        let mut moved = false;
+        for _ in 0..count {
+            match settled.unacked.pop_front() {
+                Some(sequence) => moved |= settled.settle(sequence),
+                None => break,
+            }
+        }
+        drop(settled);
+        if moved {
+            self.wake();
+        }
+    }
+
+    /// Settles item `sequence` without it being acknowledged, as the channel discarded it.
+    pub(crate) fn settle(&self, sequence: u64) {
+        if self.mode == AckMode::None {
+            return;
+        }
+        let moved = self.settled.lock().unwrap().settle(sequence);
+        if moved {
+            self.wake();
+        }
+    }
+
+    pub(crate) fn add_acker(&self) {
+        self.ackers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn remove_acker(&self) {
+        if self.ackers.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.wake();
+        }
+    }
+
+    /// Resolves once every item up to and including `sequence` has been settled, or once
+    /// nothing is left that could acknowledge it.
+    pub(crate) fn poll_acked(&self, sequence: u64, cx: &mut Context<'_>) -> Poll<()> {
+        if self.is_settled(sequence) {
+            return Poll::Ready(());
+        }
+
+        let mut wakers = self.wakers.lock().unwrap();
+        if self.is_settled(sequence) {
+            return Poll::Ready(());
+        }
+        if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+
+    fn is_settled(&self, sequence: u64) -> bool {
+        self.settled.lock().unwrap().through >= sequence || self.ackers.load(Ordering::SeqCst) == 0
+    }
+
+    fn wake(&self) {
+        let wakers = std::mem::take(&mut *self.wakers.lock().unwrap());
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Handle for acknowledging items under `AckMode::Explicit`, obtained from
+/// `PipelineReceiver::acker`.
+///
+/// Acknowledgements are cumulative and in order: `ack(n)` marks the `n` oldest items yielded by
+/// the receiver and not yet acknowledged as done.
+#[derive(Debug)]
+pub struct Acker {
+    shared: Arc<Shared>,
+}
+
+impl Acker {
+    pub(crate) fn new(shared: Arc<Shared>) -> Self {
+        shared.acks().add_acker();
+        Self { shared }
+    }
+
+    pub fn ack(&self, count: u64) {
+        self.shared.acks().ack(count);
+    }
+}
+
+impl Clone for Acker {
+    fn clone(&self) -> Self {
+        Self::new(Arc::clone(&self.shared))
+    }
+}
+
+impl Drop for Acker {
+    fn drop(&mut self) {
+        self.shared.acks().remove_acker();
+    }
+}
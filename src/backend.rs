@@ -0,0 +1,197 @@
+use futures::{task::Poll, Stream};
+use std::{pin::Pin, task::Context};
+
+/// The channel underneath a `Pipeline` and its `PipelineReceiver`.
+///
+/// Implementations are marker types selecting a channel flavour; pick one with
+/// `PipelineBuilder::backend`. The sender side must support reserving capacity ahead of sending,
+/// which is what lets `Pipeline` hand rejected items back instead of losing them.
+pub trait ChannelBackend: 'static {
+    type Sender<T>: BackendSender<T>;
+    type Receiver<T>: BackendReceiver<T>;
+
+    fn channel<T>(capacity: usize) -> (Self::Sender<T>, Self::Receiver<T>);
+}
+
+/// Returned when reserving capacity on a channel whose receiver is gone.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChannelClosed;
+
+/// Returned when an item could not be placed in a channel.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    Full(T),
+    Closed(T),
+}
+
+pub trait BackendSender<T>: Clone + Unpin {
+    /// Reserves a slot for the next `try_send`, which must then succeed unless the channel has
+    /// been closed in the meantime. Repeated calls keep the same reservation.
+    fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ChannelClosed>>;
+
+    /// Sends into the reserved slot, or into any free slot if none was reserved.
+    fn try_send(&mut self, item: T) -> Result<(), TrySendError<T>>;
+}
+
+pub trait BackendReceiver<T>: Unpin {
+    /// Receives the next item, returning `None` once every sender is gone and the channel is
+    /// empty.
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>>;
+
+    /// Receives an item if one is ready right now.
+    fn try_recv(&mut self) -> Option<T>;
+
+    /// Stops senders from reserving or sending, keeping buffered items for the receiver.
+    fn close(&mut self);
+}
+
+/// `tokio::sync::mpsc`, the default backend.
+#[derive(Debug)]
+pub enum TokioBackend {}
+
+impl ChannelBackend for TokioBackend {
+    type Sender<T> = tokio::sync::mpsc::Sender<T>;
+    type Receiver<T> = tokio::sync::mpsc::Receiver<T>;
+
+    fn channel<T>(capacity: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+        tokio::sync::mpsc::channel(capacity)
+    }
+}
+
+impl<T> BackendSender<T> for tokio::sync::mpsc::Sender<T> {
+    fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ChannelClosed>> {
+        self.poll_ready(cx).map_err(|_| ChannelClosed)
+    }
+
+    fn try_send(&mut self, item: T) -> Result<(), TrySendError<T>> {
+        use tokio::sync::mpsc::error::TrySendError as Error;
+
+        tokio::sync::mpsc::Sender::try_send(self, item).map_err(|error| match error {
+            Error::Full(item) => TrySendError::Full(item),
+            Error::Closed(item) => TrySendError::Closed(item),
+        })
+    }
+}
+
+impl<T> BackendReceiver<T> for tokio::sync::mpsc::Receiver<T> {
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        tokio::sync::mpsc::Receiver::poll_recv(self, cx)
+    }
+
+    fn try_recv(&mut self) -> Option<T> {
+        tokio::sync::mpsc::Receiver::try_recv(self).ok()
+    }
+
+    fn close(&mut self) {
+        tokio::sync::mpsc::Receiver::close(self)
+    }
+}
+
+/// `futures::channel::mpsc`, which runs under any executor.
+///
+/// Its capacity is the requested capacity plus one slot per sender, as documented by `futures`.
+#[derive(Debug)]
+pub enum FuturesBackend {}
+
+impl ChannelBackend for FuturesBackend {
+    type Sender<T> = futures::channel::mpsc::Sender<T>;
+    type Receiver<T> = futures::channel::mpsc::Receiver<T>;
+
+    fn channel<T>(capacity: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+        futures::channel::mpsc::channel(capacity)
+    }
+}
+
+impl<T> BackendSender<T> for futures::channel::mpsc::Sender<T> {
+    fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ChannelClosed>> {
+        self.poll_ready(cx).map_err(|_| ChannelClosed)
+    }
+
+    fn try_send(&mut self, item: T) -> Result<(), TrySendError<T>> {
+        futures::channel::mpsc::Sender::try_send(self, item).map_err(|error| {
+            if error.is_full() {
+                TrySendError::Full(error.into_inner())
+            } else {
+                TrySendError::Closed(error.into_inner())
+            }
+        })
+    }
+}
+
+impl<T> BackendReceiver<T> for futures::channel::mpsc::Receiver<T> {
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        Pin::new(self).poll_next(cx)
+    }
+
+    fn try_recv(&mut self) -> Option<T> {
+        futures::channel::mpsc::Receiver::try_recv(self).ok()
+    }
+
+    fn close(&mut self) {
+        futures::channel::mpsc::Receiver::close(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChannelBackend, FuturesBackend, TokioBackend};
+    use crate::{mpmc::MpmcBackend, Pipeline, PipelineError};
+    use futures::{executor::block_on, SinkExt, StreamExt};
+    use std::thread;
+
+    fn shutdown_is_lossless<B: ChannelBackend>()
+    where
+        Pipeline<usize, B>: Send,
+    {
+        let (tx, mut rx) = Pipeline::builder(8).backend::<B>().build();
+        let shutdown = rx.shutdown_handle();
+
+        let producers = (0..4)
+            .map(|_| {
+                let mut tx = tx.clone();
+                thread::spawn(move || {
+                    block_on(async {
+                        let mut sent = 0usize;
+                        loop {
+                            match tx.send(sent).await {
+                                Ok(()) => sent += 1,
+                                Err(PipelineError::Shutdown(_)) => return sent,
+                                Err(error) => panic!("unexpected error: {}", error),
+                            }
+                        }
+                    })
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(tx);
+
+        let received = block_on(async {
+            let mut received = 0usize;
+            while rx.next().await.is_some() {
+                received += 1;
+                if received == 100 {
+                    drop(shutdown.shutdown());
+                }
+            }
+            received
+        });
+
+        let sent: usize = producers.into_iter().map(|h| h.join().unwrap()).sum();
+        assert_eq!(sent, received);
+    }
+
+    #[test]
+    fn tokio_backend() {
+        shutdown_is_lossless::<TokioBackend>();
+    }
+
+    #[test]
+    fn futures_backend() {
+        shutdown_is_lossless::<FuturesBackend>();
+    }
+
+    #[test]
+    fn mpmc_backend() {
+        shutdown_is_lossless::<MpmcBackend>();
+    }
+}
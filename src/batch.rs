@@ -0,0 +1,266 @@
+use crate::{ByteSizeOf, ChannelBackend, Pipeline, PipelineError, TokioBackend};
+use futures::{future::poll_fn, ready, task::Poll, Sink, SinkExt};
+use std::{
+    mem,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::Context,
+    time::Duration,
+};
+use tokio::time::delay_for;
+
+/// A `Sink` that groups items into batches before sending them through a `Pipeline<Vec<T>>`.
+///
+/// A batch is sent once it holds `max_items` items, once its items add up to `max_bytes`, or
+/// once `linger` has passed since its first item, whichever comes first. `poll_flush` and
+/// `poll_close` send any partial batch, so nothing is stranded at shutdown.
+pub struct BatchingPipeline<T, B: ChannelBackend = TokioBackend> {
+    inner: Pipeline<Vec<T>, B>,
+    batch: Arc<Mutex<Batch<T, B>>>,
+    max_items: usize,
+    max_bytes: Option<usize>,
+    /// Estimates an item's size when batches have a byte limit.
+    weigh: Option<fn(&T) -> usize>,
+    linger: Option<Duration>,
+}
+
+/// The batch being filled, shared with the task that sends it once it has lingered.
+///
+/// Whoever sends the batch does so while holding the lock, so batches enter the channel in the
+/// order they were filled.
+struct Batch<T, B: ChannelBackend> {
+    items: Vec<T>,
+    bytes: usize,
+    /// Counts batches, so a linger task only ever sends the batch it was started for.
+    generation: u64,
+    /// The pipeline of the last linger task that sent a batch, for `poll_flush` to wait on.
+    lingered: Option<Pipeline<Vec<T>, B>>,
+}
+
+impl<T, B: ChannelBackend> Batch<T, B> {
+    /// Sends the batch through `pipeline` once it has room, if the batch has any items.
+    fn poll_send(
+        &mut self,
+        pipeline: &mut Pipeline<Vec<T>, B>,
+        max_items: usize,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), PipelineError<Vec<T>>>>
+    where
+        T: Send + 'static,
+    {
+        if self.items.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+
+        ready!(pipeline.poll_ready_unpin(cx))?;
+        let items = mem::replace(&mut self.items, Vec::with_capacity(max_items));
+        self.bytes = 0;
+        Poll::Ready(pipeline.start_send_unpin(items))
+    }
+}
+
+impl<T, B: ChannelBackend> BatchingPipeline<T, B> {
+    pub fn new(inner: Pipeline<Vec<T>, B>, max_items: usize) -> Self {
+        assert!(max_items > 0, "batches must hold at least one item");
+        Self {
+            inner,
+            batch: Arc::new(Mutex::new(Batch {
+                items: Vec::with_capacity(max_items),
+                bytes: 0,
+                generation: 0,
+                lingered: None,
+            })),
+            max_items,
+            max_bytes: None,
+            weigh: None,
+            linger: None,
+        }
+    }
+
+    /// Requires a tokio runtime with the timer enabled. Each batch that lingers is sent by a
+    /// task spawned onto it, whether or not the sink is polled again.
+    pub fn linger(mut self, linger: Duration) -> Self {
+        self.linger = Some(linger);
+        self
+    }
+
+    fn is_full(&self) -> bool {
+        let batch = self.batch.lock().unwrap();
+        batch.items.len() >= self.max_items || self.max_bytes.is_some_and(|max| batch.bytes >= max)
+    }
+}
+
+impl<T: ByteSizeOf, B: ChannelBackend> BatchingPipeline<T, B> {
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self.weigh = Some(T::size_of);
+        self
+    }
+}
+
+impl<T: Send + 'static, B: ChannelBackend> BatchingPipeline<T, B> {
+    /// Sends the current batch, if there is one.
+    fn poll_send_batch(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), PipelineError<Vec<T>>>> {
+        let mut batch = self.batch.lock().unwrap();
+        batch.poll_send(&mut self.inner, self.max_items, cx)
+    }
+
+    /// Waits for what this sink sent, then for what linger tasks sent on its behalf.
+    fn poll_flush_lingered(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<(), PipelineError<Vec<T>>>> {
+        let mut batch = self.batch.lock().unwrap();
+        if let Some(lingered) = batch.lingered.as_mut() {
+            ready!(lingered.poll_flush_unpin(cx))?;
+            batch.lingered = None;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T, B> BatchingPipeline<T, B>
+where
+    T: Send + 'static,
+    B: ChannelBackend,
+    Pipeline<Vec<T>, B>: Send,
+{
+    /// Sends the batch numbered `generation` once `linger` has passed, unless it was sent first.
+    fn spawn_linger(&self, generation: u64, linger: Duration) {
+        let batch = Arc::clone(&self.batch);
+        let mut pipeline = self.inner.clone();
+        let max_items = self.max_items;
+        tokio::spawn(async move {
+            delay_for(linger).await;
+            poll_fn(|cx| {
+                let mut batch = batch.lock().unwrap();
+                if batch.generation != generation {
+                    return Poll::Ready(());
+                }
+                let bytes = batch.bytes;
+                match ready!(batch.poll_send(&mut pipeline, max_items, cx)) {
+                    Ok(()) => batch.lingered = Some(pipeline.clone()),
+                    // Put the batch back, for the sink to hand back with the error.
+                    Err(error) => {
+                        batch.items = error.into_inner();
+                        batch.bytes = bytes;
+                    }
+                }
+                Poll::Ready(())
+            })
+            .await;
+        });
+    }
+}
+
+impl<T, B> Sink<T> for BatchingPipeline<T, B>
+where
+    T: Send + Unpin + 'static,
+    B: ChannelBackend,
+    Pipeline<Vec<T>, B>: Send,
+{
+    type Error = PipelineError<Vec<T>>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.is_full() {
+            ready!(self.poll_send_batch(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        let mut batch = self.batch.lock().unwrap();
+        if batch.items.is_empty() {
+            batch.generation += 1;
+            if let Some(linger) = self.linger {
+                self.spawn_linger(batch.generation, linger);
+            }
+        }
+        if let Some(weigh) = self.weigh {
+            batch.bytes += weigh(&item);
+        }
+        batch.items.push(item);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_send_batch(cx))?;
+        ready!(self.inner.poll_flush_unpin(cx))?;
+        self.poll_flush_lingered(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_send_batch(cx))?;
+        ready!(self.inner.poll_close_unpin(cx))?;
+        self.poll_flush_lingered(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BatchingPipeline;
+    use crate::Pipeline;
+    use futures::{executor::block_on, stream, SinkExt, StreamExt};
+    use std::time::Duration;
+
+    #[test]
+    fn batches_by_count_and_flushes_remainder_on_close() {
+        let (tx, rx) = Pipeline::bounded(8);
+        let mut batcher = BatchingPipeline::new(tx, 2);
+
+        // Items need no size unless batches have a byte limit.
+        let items = vec![Ok((1u32, 1u32)), Ok((2, 2)), Ok((3, 3))];
+        block_on(batcher.send_all(&mut stream::iter(items))).unwrap();
+        block_on(batcher.close()).unwrap();
+        drop(batcher);
+
+        assert_eq!(
+            block_on(rx.collect::<Vec<_>>()),
+            vec![vec![(1, 1), (2, 2)], vec![(3, 3)]]
+        );
+    }
+
+    #[test]
+    fn batches_by_size() {
+        let (tx, rx) = Pipeline::bounded(8);
+        let mut batcher = BatchingPipeline::new(tx, 100).max_bytes(60);
+
+        block_on(async {
+            for item in ["a", "b", "c"] {
+                batcher.feed(item.repeat(10)).await.unwrap();
+            }
+            batcher.close().await.unwrap();
+        });
+        drop(batcher);
+
+        let sizes = block_on(rx.map(|batch| batch.len()).collect::<Vec<_>>());
+        assert_eq!(sizes, vec![2, 1]);
+    }
+
+    #[tokio::test]
+    async fn batches_by_linger() {
+        let (tx, mut rx) = Pipeline::bounded(8);
+        let mut batcher = BatchingPipeline::new(tx, 100).linger(Duration::from_millis(10));
+
+        batcher.feed(1u32).await.unwrap();
+        tokio::time::delay_for(Duration::from_millis(20)).await;
+        batcher.feed(2).await.unwrap();
+
+        assert_eq!(rx.next().await, Some(vec![1]));
+    }
+
+    #[tokio::test]
+    async fn lingering_batch_is_sent_without_further_items() {
+        let (tx, mut rx) = Pipeline::bounded(8);
+        let mut batcher = BatchingPipeline::new(tx, 100).linger(Duration::from_millis(10));
+
+        batcher.feed(1u32).await.unwrap();
+        batcher.feed(2).await.unwrap();
+
+        // Nothing polls the batcher again, yet the batch arrives.
+        assert_eq!(rx.next().await, Some(vec![1, 2]));
+        batcher.close().await.unwrap();
+        drop(batcher);
+        assert_eq!(rx.next().await, None);
+    }
+}
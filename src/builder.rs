@@ -0,0 +1,179 @@
+use crate::{
+    backend::{ChannelBackend, TokioBackend},
+    pipeline::SendConfig,
+    receiver::PipelineReceiver,
+    shutdown::Shared,
+    AckMode, ByteSizeOf, DeadLetterSink, MetricsRecorder, Pipeline,
+};
+use std::{
+    fmt,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// What a `Pipeline` does with a new item when its channel is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Wait in `poll_ready` until there is room, applying backpressure to the producer.
+    #[default]
+    Block,
+    /// Accept and discard the new item.
+    DropNewest,
+    /// Discard the oldest item still in the channel to make room for the new one.
+    DropOldest,
+}
+
+/// Builder for a `Pipeline` and its `PipelineReceiver`, created by `Pipeline::builder`.
+pub struct PipelineBuilder<T, B: ChannelBackend = TokioBackend> {
+    capacity: usize,
+    max_bytes: Option<usize>,
+    weigh: Option<fn(&T) -> usize>,
+    when_full: WhenFull,
+    send_timeout: Option<Duration>,
+    max_age: Option<Duration>,
+    metrics: Option<Arc<dyn MetricsRecorder>>,
+    ack_mode: AckMode,
+    dead_letters: Option<Arc<dyn DeadLetterSink<T>>>,
+    _item: PhantomData<fn() -> (T, B)>,
+}
+
+impl<T> PipelineBuilder<T> {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            max_bytes: None,
+            weigh: None,
+            when_full: WhenFull::default(),
+            send_timeout: None,
+            max_age: None,
+            metrics: None,
+            ack_mode: AckMode::default(),
+            dead_letters: None,
+            _item: PhantomData,
+        }
+    }
+}
+
+impl<T, B: ChannelBackend> PipelineBuilder<T, B> {
+    /// Selects the channel implementation, `TokioBackend` by default.
+    pub fn backend<B2: ChannelBackend>(self) -> PipelineBuilder<T, B2> {
+        PipelineBuilder {
+            capacity: self.capacity,
+            max_bytes: self.max_bytes,
+            weigh: self.weigh,
+            when_full: self.when_full,
+            send_timeout: self.send_timeout,
+            max_age: self.max_age,
+            metrics: self.metrics,
+            ack_mode: self.ack_mode,
+            dead_letters: self.dead_letters,
+            _item: PhantomData,
+        }
+    }
+
+    pub fn when_full(mut self, when_full: WhenFull) -> Self {
+        self.when_full = when_full;
+        self
+    }
+
+    /// Makes `poll_ready` give up after waiting this long for room, so that the following
+    /// `start_send` fails with `PipelineError::Timeout`. Requires a tokio runtime with the timer
+    /// enabled.
+    pub fn send_timeout(mut self, timeout: Duration) -> Self {
+        self.send_timeout = Some(timeout);
+        self
+    }
+
+    /// Gives each item a deadline this long after it is sent. The receiver discards items whose
+    /// deadline has passed instead of yielding them, counting them as expired.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    pub fn metrics(mut self, metrics: Arc<dyn MetricsRecorder>) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// Makes `poll_flush` and `poll_close` wait for sent items to be acknowledged.
+    pub fn ack_mode(mut self, ack_mode: AckMode) -> Self {
+        self.ack_mode = ack_mode;
+        self
+    }
+
+    /// Hands items the channel discards to `dead_letters` instead of dropping them: those
+    /// discarded by the `WhenFull` policy, expired at the receiver or left behind when the
+    /// receiver is dropped.
+    ///
+    /// Items refused because the receiver is gone or shut down, or because the send timeout
+    /// passed, are handed back by `start_send` inside the `PipelineError` instead. To have them
+    /// routed here as well, send through a `DeadLetteringPipeline`, whose errors only tell why.
+    /// An item parked on `max_bytes` was already accepted, so it is always dead-lettered if it
+    /// can't be sent.
+    pub fn dead_letters(mut self, dead_letters: Arc<dyn DeadLetterSink<T>>) -> Self {
+        self.dead_letters = Some(dead_letters);
+        self
+    }
+
+    pub fn build(self) -> (Pipeline<T, B>, PipelineReceiver<T, B>) {
+        let (tx, rx) = B::channel(self.capacity);
+        let rx = Arc::new(Mutex::new(rx));
+        let shared = Arc::new(Shared::new(
+            self.capacity,
+            self.max_bytes,
+            self.metrics,
+            self.ack_mode,
+        ));
+
+        // Only a pipeline that drops the oldest item needs to reach into the receiver.
+        let evict = match self.when_full {
+            WhenFull::DropOldest => Some(Arc::clone(&rx)),
+            WhenFull::Block | WhenFull::DropNewest => None,
+        };
+        let config = SendConfig {
+            when_full: self.when_full,
+            weigh: self.weigh,
+            send_timeout: self.send_timeout,
+            max_age: self.max_age,
+            dead_letters: self.dead_letters.clone(),
+        };
+        let pipeline = Pipeline::from_parts(tx, Arc::clone(&shared), config, evict);
+        let receiver = PipelineReceiver::new(rx, shared, self.dead_letters);
+        (pipeline, receiver)
+    }
+}
+
+impl<T: ByteSizeOf, B: ChannelBackend> PipelineBuilder<T, B> {
+    /// Limits the channel to `max_bytes` worth of items as estimated by `ByteSizeOf`, on top of
+    /// the item capacity.
+    ///
+    /// Each item is charged its size before it enters the channel, and only if it fits, so the
+    /// limit holds however many clones are sending. An item bigger than `max_bytes` is let
+    /// through once the channel holds no other bytes.
+    ///
+    /// Its size isn't known until `start_send`, so an item that doesn't fit is parked in its
+    /// `Pipeline` under `WhenFull::Block`, and the next `poll_ready`, `poll_flush` or `poll_close`
+    /// waits until enough bytes have been received to send it, or sends it over the limit once
+    /// the send timeout passes. Under `WhenFull::DropNewest` it is discarded instead, and under
+    /// `WhenFull::DropOldest` older items are evicted to make room.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self.weigh = Some(T::size_of);
+        self
+    }
+}
+
+impl<T, B: ChannelBackend> fmt::Debug for PipelineBuilder<T, B> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("PipelineBuilder")
+            .field("capacity", &self.capacity)
+            .field("max_bytes", &self.max_bytes)
+            .field("when_full", &self.when_full)
+            .field("send_timeout", &self.send_timeout)
+            .field("max_age", &self.max_age)
+            .field("ack_mode", &self.ack_mode)
+            .finish()
+    }
+}
@@ -0,0 +1,50 @@
+/// Estimates how many bytes of memory an item holds, for size-based limits.
+pub trait ByteSizeOf {
+    /// Size of the value itself plus anything it owns on the heap.
+    fn size_of(&self) -> usize {
+        std::mem::size_of_val(self) + self.allocated_bytes()
+    }
+
+    /// Size of what the value owns on the heap.
+    fn allocated_bytes(&self) -> usize;
+}
+
+impl ByteSizeOf for String {
+    fn allocated_bytes(&self) -> usize {
+        self.len()
+    }
+}
+
+impl<T: ByteSizeOf> ByteSizeOf for Vec<T> {
+    fn allocated_bytes(&self) -> usize {
+        self.iter().map(ByteSizeOf::size_of).sum()
+    }
+}
+
+impl<T: ByteSizeOf> ByteSizeOf for Box<T> {
+    fn allocated_bytes(&self) -> usize {
+        (**self).size_of()
+    }
+}
+
+impl<T: ByteSizeOf> ByteSizeOf for Option<T> {
+    fn allocated_bytes(&self) -> usize {
+        self.as_ref().map_or(0, ByteSizeOf::allocated_bytes)
+    }
+}
+
+macro_rules! impl_byte_size_of_for_primitive {
+    ($($ty:ty),*) => {
+        $(
+            impl ByteSizeOf for $ty {
+                fn allocated_bytes(&self) -> usize {
+                    0
+                }
+            }
+        )*
+    };
+}
+
+impl_byte_size_of_for_primitive!(
+    bool, char, u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64
+);
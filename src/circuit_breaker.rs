@@ -0,0 +1,395 @@
+use crate::{DeadLetter, DeadLetterReason, DeadLetterSink};
+use futures::{task::Poll, FutureExt, Sink, SinkExt};
+use std::{
+    collections::VecDeque,
+    fmt,
+    pin::Pin,
+    sync::Arc,
+    task::Context,
+    time::{Duration, Instant},
+};
+use tokio::time::{delay_for, Delay};
+
+const DEFAULT_WINDOW: usize = 20;
+const DEFAULT_ERROR_RATE: f64 = 0.5;
+const DEFAULT_OPEN_FOR: Duration = Duration::from_secs(1);
+
+/// The state of a `CircuitBreaker`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CircuitState {
+    /// Items go through to the inner sink.
+    Closed,
+    /// The inner sink is considered unhealthy, and items are turned away without touching it.
+    Open,
+    /// A few items go through to find out whether the inner sink has recovered.
+    HalfOpen,
+}
+
+/// A change of state, as passed to the `CircuitBreaker::on_transition` callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Transition {
+    pub from: CircuitState,
+    pub to: CircuitState,
+    pub at: Instant,
+}
+
+type Listener = Arc<dyn Fn(&Transition) + Send + Sync>;
+
+/// A `Sink` whose pending `poll_ready` holds on to something, like a reservation in a channel,
+/// that should be given up if no item follows.
+pub trait CancelReady {
+    /// Gives up whatever a pending `poll_ready` is holding. The next `poll_ready` starts over.
+    fn cancel_ready(&mut self);
+}
+
+/// A `Sink` that stops feeding an unhealthy inner sink for a while, rather than waiting on it.
+///
+/// The breaker opens once at least half of the last `window` items failed, as set by
+/// `error_rate`, or once `poll_ready` on the inner sink has been pending for longer than
+/// `max_pending`. While open, `poll_ready` resolves at once and items are shed, or handed to the
+/// `divert` sink if there is one. After `open_for` it goes half open and lets `probes` items
+/// through: if they all succeed it closes again, and the first failure opens it again.
+///
+/// An item fails if the inner sink returns an error for it, which is passed on as usual. Requires
+/// a tokio runtime with the timer enabled when `max_pending` is set. Opening on `max_pending`
+/// cancels the pending `poll_ready` on the inner sink, so that a `Pipeline` doesn't hold up its
+/// receiver's shutdown while the breaker is open.
+pub struct CircuitBreaker<S, T> {
+    inner: S,
+    state: CircuitState,
+    /// Whether each of the last `window` items failed, oldest first.
+    outcomes: VecDeque<bool>,
+    window: usize,
+    error_rate: f64,
+    max_pending: Option<Duration>,
+    /// Called when the breaker opens on `max_pending`, set along with it.
+    cancel_ready: fn(&mut S),
+    open_for: Duration,
+    probes: usize,
+    /// Probes that succeeded since going half open.
+    probed: usize,
+    opened_at: Instant,
+    /// Running while the inner sink's `poll_ready` is pending, when there is a `max_pending`.
+    timer: Option<Delay>,
+    shed: u64,
+    divert: Option<Arc<dyn DeadLetterSink<T>>>,
+    listener: Option<Listener>,
+}
+
+impl<S, T> CircuitBreaker<S, T> {
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            state: CircuitState::Closed,
+            outcomes: VecDeque::new(),
+            window: DEFAULT_WINDOW,
+            error_rate: DEFAULT_ERROR_RATE,
+            max_pending: None,
+            cancel_ready: |_| {},
+            open_for: DEFAULT_OPEN_FOR,
+            probes: 1,
+            probed: 0,
+            opened_at: Instant::now(),
+            timer: None,
+            shed: 0,
+            divert: None,
+            listener: None,
+        }
+    }
+
+    /// Number of recent items the error rate is worked out over. The breaker won't open on
+    /// errors until it has seen this many.
+    pub fn window(mut self, window: usize) -> Self {
+        assert!(window > 0, "window must hold at least one item");
+        self.window = window;
+        self
+    }
+
+    /// Fraction of failed items in the window at which the breaker opens.
+    pub fn error_rate(mut self, error_rate: f64) -> Self {
+        assert!(
+            error_rate > 0.0 && error_rate <= 1.0,
+            "error rate must be above 0 and at most 1"
+        );
+        self.error_rate = error_rate;
+        self
+    }
+
+    /// How long the breaker stays open before probing the inner sink.
+    pub fn open_for(mut self, open_for: Duration) -> Self {
+        self.open_for = open_for;
+        self
+    }
+
+    /// Number of items that must succeed while half open for the breaker to close.
+    pub fn probes(mut self, probes: usize) -> Self {
+        assert!(probes > 0, "at least one probe is needed");
+        self.probes = probes;
+        self
+    }
+
+    /// Hands items turned away while open to `divert` rather than dropping them.
+    pub fn divert(mut self, divert: Arc<dyn DeadLetterSink<T>>) -> Self {
+        self.divert = Some(divert);
+        self
+    }
+
+    /// Calls `listener` on every change of state.
+    pub fn on_transition(mut self, listener: impl Fn(&Transition) + Send + Sync + 'static) -> Self {
+        self.listener = Some(Arc::new(listener));
+        self
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+
+    /// Number of items turned away while open, including diverted ones.
+    pub fn shed(&self) -> u64 {
+        self.shed
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn transition(&mut self, to: CircuitState) {
+        let from = std::mem::replace(&mut self.state, to);
+        match to {
+            CircuitState::Open => self.opened_at = Instant::now(),
+            CircuitState::HalfOpen => self.probed = 0,
+            CircuitState::Closed => self.outcomes.clear(),
+        }
+        self.timer = None;
+        if let Some(listener) = &self.listener {
+            listener(&Transition {
+                from,
+                to,
+                at: Instant::now(),
+            });
+        }
+    }
+
+    fn record(&mut self, failed: bool) {
+        match self.state {
+            CircuitState::Closed => {
+                if self.outcomes.len() == self.window {
+                    self.outcomes.pop_front();
+                }
+                self.outcomes.push_back(failed);
+                let failures = self.outcomes.iter().filter(|&&failed| failed).count();
+                if self.outcomes.len() == self.window
+                    && failures as f64 >= self.error_rate * self.window as f64
+                {
+                    self.transition(CircuitState::Open);
+                }
+            }
+            CircuitState::HalfOpen if failed => self.transition(CircuitState::Open),
+            CircuitState::HalfOpen => {
+                self.probed += 1;
+                if self.probed >= self.probes {
+                    self.transition(CircuitState::Closed);
+                }
+            }
+            CircuitState::Open => {}
+        }
+    }
+
+    /// Opens the breaker once the inner sink has been pending for longer than `max_pending`.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let max_pending = match self.max_pending {
+            Some(max_pending) => max_pending,
+            None => return Poll::Pending,
+        };
+        let timer = self.timer.get_or_insert_with(|| delay_for(max_pending));
+        if timer.poll_unpin(cx).is_pending() {
+            return Poll::Pending;
+        }
+        (self.cancel_ready)(&mut self.inner);
+        self.transition(CircuitState::Open);
+        Poll::Ready(())
+    }
+}
+
+impl<S: CancelReady, T> CircuitBreaker<S, T> {
+    /// Opens the breaker when the inner sink has no room for this long.
+    pub fn max_pending(mut self, max_pending: Duration) -> Self {
+        self.max_pending = Some(max_pending);
+        self.cancel_ready = S::cancel_ready;
+        self
+    }
+}
+
+impl<S, T> Sink<T> for CircuitBreaker<S, T>
+where
+    S: Sink<T> + Unpin,
+{
+    type Error = S::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.state == CircuitState::Open {
+            if self.opened_at.elapsed() < self.open_for {
+                return Poll::Ready(Ok(()));
+            }
+            self.transition(CircuitState::HalfOpen);
+        }
+        match self.inner.poll_ready_unpin(cx) {
+            Poll::Ready(Ok(())) => {
+                self.timer = None;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(error)) => {
+                self.timer = None;
+                self.record(true);
+                Poll::Ready(Err(error))
+            }
+            Poll::Pending => self.poll_pending(cx).map(Ok),
+        }
+    }
+
+    /// While open, sheds the item and succeeds.
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        if self.state == CircuitState::Open {
+            self.shed += 1;
+            if let Some(divert) = &self.divert {
+                divert.dead_letter(DeadLetter::new(item, DeadLetterReason::CircuitOpen));
+            }
+            return Ok(());
+        }
+        let result = self.inner.start_send_unpin(item);
+        self.record(result.is_err());
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+impl<S: fmt::Debug, T> fmt::Debug for CircuitBreaker<S, T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("CircuitBreaker")
+            .field("inner", &self.inner)
+            .field("state", &self.state)
+            .field("window", &self.window)
+            .field("error_rate", &self.error_rate)
+            .field("max_pending", &self.max_pending)
+            .field("open_for", &self.open_for)
+            .field("shed", &self.shed)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CircuitBreaker, CircuitState};
+    use crate::{DeadLetterReason, InMemoryDeadLetters, Pipeline};
+    use futures::{executor::block_on, SinkExt, StreamExt};
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+    use tokio::time::timeout;
+
+    #[test]
+    fn opens_on_errors_and_closes_after_probing() {
+        let (tx, rx) = Pipeline::bounded(16);
+        let transitions = Arc::new(Mutex::new(Vec::new()));
+        let mut breaker = CircuitBreaker::new(tx)
+            .window(4)
+            .open_for(Duration::from_millis(10))
+            .on_transition({
+                let transitions = Arc::clone(&transitions);
+                move |t| transitions.lock().unwrap().push((t.from, t.to))
+            });
+
+        // Two failures out of four trips the breaker.
+        block_on(breaker.feed(1)).unwrap();
+        block_on(breaker.feed(2)).unwrap();
+        let shutdown = rx.shutdown_handle();
+        drop(shutdown.shutdown());
+        assert!(block_on(breaker.feed(3)).is_err());
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(block_on(breaker.feed(4)).is_err());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // Shed without touching the pipeline while open.
+        block_on(breaker.feed(5)).unwrap();
+        assert_eq!(breaker.shed(), 1);
+
+        // The probe fails against the shut down pipeline, so it opens again.
+        std::thread::sleep(Duration::from_millis(15));
+        assert!(block_on(breaker.feed(6)).is_err());
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        let (tx, _rx) = Pipeline::bounded(16);
+        let mut breaker = CircuitBreaker::new(tx).window(1).open_for(Duration::ZERO);
+        breaker.transition(CircuitState::Open);
+        block_on(breaker.feed(7)).unwrap();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        assert_eq!(
+            *transitions.lock().unwrap(),
+            [
+                (CircuitState::Closed, CircuitState::Open),
+                (CircuitState::Open, CircuitState::HalfOpen),
+                (CircuitState::HalfOpen, CircuitState::Open),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn opens_when_pending_too_long_and_diverts() {
+        let (tx, mut rx) = Pipeline::bounded(1);
+        let diverted = Arc::new(InMemoryDeadLetters::new(8));
+        let mut breaker = CircuitBreaker::new(tx)
+            .max_pending(Duration::from_millis(5))
+            .divert(diverted.clone());
+
+        breaker.send(1).await.unwrap();
+        breaker.send(2).await.unwrap();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        breaker.send(3).await.unwrap();
+
+        let diverted = diverted.drain();
+        assert_eq!(
+            diverted
+                .iter()
+                .map(|letter| (letter.item, letter.reason))
+                .collect::<Vec<_>>(),
+            [
+                (2, DeadLetterReason::CircuitOpen),
+                (3, DeadLetterReason::CircuitOpen)
+            ]
+        );
+        drop(breaker);
+        assert_eq!(rx.next().await, Some(1));
+        assert_eq!(rx.next().await, None);
+    }
+
+    #[tokio::test]
+    async fn opening_on_max_pending_lets_the_receiver_shut_down() {
+        let (tx, rx) = Pipeline::bounded(1);
+        let mut breaker = CircuitBreaker::new(tx)
+            .max_pending(Duration::from_millis(5))
+            .open_for(Duration::from_secs(60));
+
+        breaker.send(1).await.unwrap();
+        breaker.send(2).await.unwrap();
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        // The breaker is still around, but holds no reservation that would keep the receiver
+        // waiting.
+        rx.begin_shutdown();
+        let drained = timeout(Duration::from_secs(5), rx.collect::<Vec<_>>()).await;
+        assert_eq!(drained.unwrap(), [1]);
+    }
+}
@@ -0,0 +1,205 @@
+use crate::shutdown::{Shutdown, ShutdownHandle};
+use futures::{future::poll_fn, task::Poll, FutureExt};
+use std::{fmt, time::Duration};
+use tokio::time::delay_for;
+
+struct Stage {
+    name: String,
+    handle: ShutdownHandle,
+    /// Indices of the stages feeding this one, all registered before it.
+    upstream: Vec<usize>,
+}
+
+/// Shuts down a graph of stages, each fed by a `Pipeline`, in dependency order.
+///
+/// A stage is registered with the `ShutdownHandle` of its input and the names of the stages that
+/// feed it. Stages with nothing upstream are fed by sources, and are shut down first. Every other
+/// stage is left to drain on its own, which it does once its upstream stages have drained and
+/// the producers between them have finished and dropped their `Pipeline`s.
+///
+/// Stages still not drained when the deadline passes are force stopped: their receivers end at
+/// once, and whatever was still in their channels is abandoned.
+#[derive(Default)]
+pub struct ShutdownCoordinator {
+    stages: Vec<Stage>,
+}
+
+impl ShutdownCoordinator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers a stage fed by the channel behind `handle`.
+    ///
+    /// Panics if a stage named in `upstream` hasn't been registered yet.
+    pub fn add_stage(&mut self, name: &str, handle: ShutdownHandle, upstream: &[&str]) {
+        let upstream = upstream
+            .iter()
+            .map(|name| {
+                self.position(name)
+                    .unwrap_or_else(|| panic!("unknown upstream stage {:?}", name))
+            })
+            .collect();
+        self.stages.push(Stage {
+            name: name.to_string(),
+            handle,
+            upstream,
+        });
+    }
+
+    pub(crate) fn has_stage(&self, name: &str) -> bool {
+        self.position(name).is_some()
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.stages.iter().position(|stage| stage.name == name)
+    }
+
+    /// Shuts every stage down, force stopping whatever hasn't drained within `deadline`.
+    ///
+    /// Requires a tokio runtime with the timer enabled.
+    pub async fn shutdown(&self, deadline: Duration) -> ShutdownReport {
+        let mut draining: Vec<Option<Shutdown>> = self.stages.iter().map(|_| None).collect();
+        let mut done = vec![false; self.stages.len()];
+        let mut timer = delay_for(deadline);
+
+        let timed_out = poll_fn(|cx| {
+            let mut progress = true;
+            while progress {
+                progress = false;
+                for (index, stage) in self.stages.iter().enumerate() {
+                    if done[index] {
+                        continue;
+                    }
+                    if draining[index].is_none() {
+                        if !stage.upstream.iter().all(|&upstream| done[upstream]) {
+                            continue;
+                        }
+                        draining[index] = Some(if stage.upstream.is_empty() {
+                            stage.handle.shutdown()
+                        } else {
+                            stage.handle.drained()
+                        });
+                    }
+                    if draining[index].as_mut().unwrap().poll_unpin(cx).is_ready() {
+                        done[index] = true;
+                        progress = true;
+                    }
+                }
+            }
+
+            if done.iter().all(|&done| done) {
+                Poll::Ready(false)
+            } else {
+                timer.poll_unpin(cx).map(|()| true)
+            }
+        })
+        .await;
+
+        let stages = self
+            .stages
+            .iter()
+            .zip(done)
+            .map(|(stage, done)| {
+                let abandoned = if timed_out && !done {
+                    Some(stage.handle.force_stop())
+                } else {
+                    None
+                };
+                StageReport {
+                    name: stage.name.clone(),
+                    delivered: stage.handle.delivered(),
+                    abandoned: abandoned.unwrap_or(0),
+                    forced: abandoned.is_some(),
+                }
+            })
+            .collect();
+        ShutdownReport { stages }
+    }
+}
+
+impl fmt::Debug for ShutdownCoordinator {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("ShutdownCoordinator")
+            .field(
+                "stages",
+                &self.stages.iter().map(|s| &s.name).collect::<Vec<_>>(),
+            )
+            .finish()
+    }
+}
+
+/// What became of each stage during `ShutdownCoordinator::shutdown`, in registration order.
+#[derive(Clone, Debug)]
+pub struct ShutdownReport {
+    pub stages: Vec<StageReport>,
+}
+
+impl ShutdownReport {
+    /// Total number of items abandoned by force stopped stages.
+    pub fn abandoned(&self) -> usize {
+        self.stages.iter().map(|stage| stage.abandoned).sum()
+    }
+
+    /// Whether every stage drained before the deadline.
+    pub fn is_clean(&self) -> bool {
+        self.stages.iter().all(|stage| !stage.forced)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StageReport {
+    pub name: String,
+    /// Items the stage received over its lifetime.
+    pub delivered: usize,
+    /// Items left in the stage's channel when it was force stopped.
+    pub abandoned: usize,
+    pub forced: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ShutdownCoordinator;
+    use crate::Pipeline;
+    use futures::{SinkExt, StreamExt};
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn drains_stages_in_order() {
+        let (mut source, first) = Pipeline::bounded(16);
+        let (second_tx, second) = Pipeline::bounded(16);
+        let mut coordinator = ShutdownCoordinator::new();
+        coordinator.add_stage("first", first.shutdown_handle(), &[]);
+        coordinator.add_stage("second", second.shutdown_handle(), &["first"]);
+
+        for i in 0..10u32 {
+            source.feed(i).await.unwrap();
+        }
+        let forward = tokio::spawn(first.map(Ok).forward(second_tx));
+        let collect = tokio::spawn(second.collect::<Vec<_>>());
+
+        let report = coordinator.shutdown(Duration::from_secs(5)).await;
+        assert!(report.is_clean());
+        assert_eq!(report.abandoned(), 0);
+        assert_eq!(report.stages[1].delivered, 10);
+        forward.await.unwrap().unwrap();
+        assert_eq!(collect.await.unwrap(), (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn force_stops_stages_past_the_deadline() {
+        let (mut tx, mut rx) = Pipeline::bounded(4);
+        let mut coordinator = ShutdownCoordinator::new();
+        coordinator.add_stage("stuck", rx.shutdown_handle(), &[]);
+        for i in 0..3u32 {
+            tx.feed(i).await.unwrap();
+        }
+
+        let report = coordinator.shutdown(Duration::from_millis(10)).await;
+        assert!(!report.is_clean());
+        assert_eq!(report.abandoned(), 3);
+        assert!(report.stages[0].forced);
+        assert_eq!(rx.next().await, None);
+        assert!(tx.send(4).await.is_err());
+    }
+}
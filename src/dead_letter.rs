@@ -0,0 +1,524 @@
+use crate::{
+    disk::read_record, ChannelBackend, Codec, ErrorKind, Pipeline, PipelineError, TokioBackend,
+};
+use futures::{task::Poll, Sink, SinkExt};
+use std::{
+    collections::VecDeque,
+    convert::TryInto,
+    fmt,
+    fs::{File, OpenOptions},
+    io::{self, BufReader, Write},
+    marker::PhantomData,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    task::Context,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Why an item ended up in a dead-letter sink.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeadLetterReason {
+    /// A `Pipeline` refused the item, and it was dead-lettered by a `DeadLetteringPipeline` or
+    /// by its producer through `DeadLetter::rejected`. Also used for an item parked on
+    /// `max_bytes` that could not be sent.
+    Rejected(ErrorKind),
+    /// The channel was full, and `WhenFull::DropNewest` discarded the item.
+    Dropped,
+    /// The item was the oldest in a full channel, and `WhenFull::DropOldest` discarded it.
+    Evicted,
+    /// The item's deadline passed before it was received.
+    Expired,
+    /// The item was still in the channel when its receiver was dropped.
+    Abandoned,
+    /// A `CircuitBreaker` turned the item away while open.
+    CircuitOpen,
+}
+
+impl fmt::Display for DeadLetterReason {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeadLetterReason::Rejected(kind) => write!(fmt, "rejected: {}", kind),
+            DeadLetterReason::Dropped => write!(fmt, "dropped while full"),
+            DeadLetterReason::Evicted => write!(fmt, "evicted while full"),
+            DeadLetterReason::Expired => write!(fmt, "expired"),
+            DeadLetterReason::Abandoned => write!(fmt, "abandoned by receiver"),
+            DeadLetterReason::CircuitOpen => write!(fmt, "circuit open"),
+        }
+    }
+}
+
+/// An item that was never delivered, along with why and when it was given up on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeadLetter<T> {
+    pub item: T,
+    pub reason: DeadLetterReason,
+    pub at: SystemTime,
+}
+
+impl<T> DeadLetter<T> {
+    pub fn new(item: T, reason: DeadLetterReason) -> Self {
+        Self {
+            item,
+            reason,
+            at: SystemTime::now(),
+        }
+    }
+
+    /// Dead-letters the item handed back by a `Pipeline` that refused it.
+    pub fn rejected(error: PipelineError<T>) -> Self {
+        let kind = error.kind();
+        Self::new(error.into_inner(), DeadLetterReason::Rejected(kind))
+    }
+}
+
+/// Receives the items a `Pipeline` discards, configured through `PipelineBuilder::dead_letters`.
+///
+/// Items discarded by the `WhenFull` policy, expired at the receiver or left behind when the
+/// receiver is dropped end up here. Items refused by `start_send`, because the receiver is gone
+/// or the send timed out, are handed back to the producer instead, unless it sends through a
+/// `DeadLetteringPipeline`. See `PipelineBuilder::dead_letters`.
+///
+/// Called inline on the send and receive paths, though never with the channel locked, so it
+/// should not block for long.
+pub trait DeadLetterSink<T>: Send + Sync {
+    fn dead_letter(&self, letter: DeadLetter<T>);
+}
+
+/// A `Sink` that dead-letters the items its `Pipeline` refuses, rather than handing them back.
+///
+/// Items refused because the receiver is gone or shutting down, because the send timed out or
+/// because there was no room go to the pipeline's `PipelineBuilder::dead_letters` sink, or are
+/// dropped if it has none. The error only tells why.
+pub struct DeadLetteringPipeline<T, B: ChannelBackend = TokioBackend> {
+    inner: Pipeline<T, B>,
+}
+
+impl<T, B: ChannelBackend> DeadLetteringPipeline<T, B> {
+    pub fn new(inner: Pipeline<T, B>) -> Self {
+        Self { inner }
+    }
+
+    pub fn get_ref(&self) -> &Pipeline<T, B> {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> Pipeline<T, B> {
+        self.inner
+    }
+
+    fn reject(&self, error: PipelineError<T>) -> ErrorKind {
+        let kind = error.kind();
+        self.inner
+            .dead_letter(error.into_inner(), DeadLetterReason::Rejected(kind));
+        kind
+    }
+}
+
+impl<T, B> Sink<T> for DeadLetteringPipeline<T, B>
+where
+    T: Send + 'static,
+    B: ChannelBackend,
+{
+    type Error = ErrorKind;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner
+            .poll_ready_unpin(cx)
+            .map_err(|error| self.reject(error))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        self.inner
+            .start_send_unpin(item)
+            .map_err(|error| self.reject(error))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner
+            .poll_flush_unpin(cx)
+            .map_err(|error| self.reject(error))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner
+            .poll_close_unpin(cx)
+            .map_err(|error| self.reject(error))
+    }
+}
+
+impl<T, B: ChannelBackend> Clone for DeadLetteringPipeline<T, B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T, B: ChannelBackend> fmt::Debug for DeadLetteringPipeline<T, B> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("DeadLetteringPipeline").finish()
+    }
+}
+
+/// A `DeadLetterSink` keeping the most recent `capacity` letters in memory.
+pub struct InMemoryDeadLetters<T> {
+    capacity: usize,
+    letters: Mutex<VecDeque<DeadLetter<T>>>,
+    overflowed: AtomicU64,
+}
+
+impl<T> InMemoryDeadLetters<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "capacity must be at least one");
+        Self {
+            capacity,
+            letters: Mutex::new(VecDeque::new()),
+            overflowed: AtomicU64::new(0),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.letters.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Number of letters discarded to make room for newer ones.
+    pub fn overflowed(&self) -> u64 {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+
+    /// Takes every letter held, oldest first, e.g. to replay them.
+    pub fn drain(&self) -> Vec<DeadLetter<T>> {
+        self.letters.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl<T: Clone> InMemoryDeadLetters<T> {
+    /// Copies every letter held, oldest first.
+    pub fn snapshot(&self) -> Vec<DeadLetter<T>> {
+        self.letters.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl<T: Send> DeadLetterSink<T> for InMemoryDeadLetters<T> {
+    fn dead_letter(&self, letter: DeadLetter<T>) {
+        let mut letters = self.letters.lock().unwrap();
+        if letters.len() == self.capacity {
+            letters.pop_front();
+            self.overflowed.fetch_add(1, Ordering::Relaxed);
+        }
+        letters.push_back(letter);
+    }
+}
+
+impl<T> fmt::Debug for InMemoryDeadLetters<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("InMemoryDeadLetters")
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .field("overflowed", &self.overflowed())
+            .finish()
+    }
+}
+
+/// A `DeadLetterSink` appending every letter to a local file.
+///
+/// Each letter is written as one length-prefixed record holding its timestamp, reason and the
+/// item as encoded by the `Codec`, and `read` loads them back for inspection or replay. A letter
+/// that can't be encoded or written is lost, and counted in `write_errors`.
+pub struct FileDeadLetters<T, C> {
+    path: PathBuf,
+    codec: C,
+    file: Mutex<File>,
+    write_errors: AtomicU64,
+    _item: PhantomData<fn(T)>,
+}
+
+impl<T, C: Codec<T>> FileDeadLetters<T, C> {
+    /// Opens `path` for appending, creating it if needed. Letters already in it are kept, but a
+    /// truncated record at the end, left by a crash mid-write, is cut off so that new letters
+    /// aren't appended to it.
+    pub fn open(path: impl AsRef<Path>, codec: C) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .create(true)
+            .append(true)
+            .open(&path)?;
+        let mut end = 0;
+        let mut reader = BufReader::new(&file);
+        while let Some(record) = read_record(&mut reader)? {
+            end += 4 + record.len() as u64;
+        }
+        if file.metadata()?.len() > end {
+            file.set_len(end)?;
+        }
+        Ok(Self {
+            path,
+            codec,
+            file: Mutex::new(file),
+            write_errors: AtomicU64::new(0),
+            _item: PhantomData,
+        })
+    }
+
+    /// Reads back every letter in the file at `path`, oldest first.
+    pub fn read(path: impl AsRef<Path>, codec: &C) -> io::Result<Vec<DeadLetter<T>>> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut letters = Vec::new();
+        while let Some(record) = read_record(&mut file)? {
+            letters.push(decode(&record, codec)?);
+        }
+        Ok(letters)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors.load(Ordering::Relaxed)
+    }
+
+    fn append(&self, letter: &DeadLetter<T>) -> io::Result<()> {
+        let mut buf = vec![0; 4];
+        let since_epoch = letter.at.duration_since(UNIX_EPOCH).unwrap_or_default();
+        buf.extend_from_slice(&since_epoch.as_secs().to_le_bytes());
+        buf.extend_from_slice(&since_epoch.subsec_nanos().to_le_bytes());
+        buf.push(encode_reason(letter.reason));
+        self.codec.encode(&letter.item, &mut buf)?;
+        let len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&len.to_le_bytes());
+        // Written in one go, so a crash leaves at most a truncated record at the end, which
+        // `read` ignores and `open` cuts off.
+        self.file.lock().unwrap().write_all(&buf)
+    }
+}
+
+impl<T, C> DeadLetterSink<T> for FileDeadLetters<T, C>
+where
+    C: Codec<T> + Send + Sync,
+{
+    fn dead_letter(&self, letter: DeadLetter<T>) {
+        if self.append(&letter).is_err() {
+            self.write_errors.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<T, C> fmt::Debug for FileDeadLetters<T, C> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("FileDeadLetters")
+            .field("path", &self.path)
+            .field("write_errors", &self.write_errors.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// Size of a record's timestamp and reason, ahead of the item.
+const HEADER_LEN: usize = 13;
+
+fn decode<T, C: Codec<T>>(record: &[u8], codec: &C) -> io::Result<DeadLetter<T>> {
+    if record.len() < HEADER_LEN {
+        return Err(invalid("truncated dead letter"));
+    }
+    let secs = u64::from_le_bytes(record[..8].try_into().unwrap());
+    let nanos = u32::from_le_bytes(record[8..12].try_into().unwrap());
+    Ok(DeadLetter {
+        item: codec.decode(&record[HEADER_LEN..])?,
+        reason: decode_reason(record[12])?,
+        at: UNIX_EPOCH + Duration::new(secs, nanos),
+    })
+}
+
+fn encode_reason(reason: DeadLetterReason) -> u8 {
+    match reason {
+        DeadLetterReason::Rejected(ErrorKind::Closed) => 0,
+        DeadLetterReason::Rejected(ErrorKind::Shutdown) => 1,
+        DeadLetterReason::Rejected(ErrorKind::CapacityExceeded) => 2,
+        DeadLetterReason::Rejected(ErrorKind::Timeout) => 3,
+        DeadLetterReason::Dropped => 4,
+        DeadLetterReason::Evicted => 5,
+        DeadLetterReason::Expired => 6,
+        DeadLetterReason::Abandoned => 7,
+        DeadLetterReason::CircuitOpen => 8,
+    }
+}
+
+fn decode_reason(byte: u8) -> io::Result<DeadLetterReason> {
+    Ok(match byte {
+        0 => DeadLetterReason::Rejected(ErrorKind::Closed),
+        1 => DeadLetterReason::Rejected(ErrorKind::Shutdown),
+        2 => DeadLetterReason::Rejected(ErrorKind::CapacityExceeded),
+        3 => DeadLetterReason::Rejected(ErrorKind::Timeout),
+        4 => DeadLetterReason::Dropped,
+        5 => DeadLetterReason::Evicted,
+        6 => DeadLetterReason::Expired,
+        7 => DeadLetterReason::Abandoned,
+        8 => DeadLetterReason::CircuitOpen,
+        _ => return Err(invalid("unknown dead letter reason")),
+    })
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DeadLetter, DeadLetterReason, DeadLetteringPipeline, FileDeadLetters, InMemoryDeadLetters,
+    };
+    use crate::{Codec, DeadLetterSink, ErrorKind, Pipeline, PipelineError, WhenFull};
+    use futures::{executor::block_on, SinkExt};
+    use std::{convert::TryInto, io, sync::Arc, time::Duration};
+
+    struct Le32;
+
+    impl Codec<u32> for Le32 {
+        fn encode(&self, item: &u32, buf: &mut Vec<u8>) -> io::Result<()> {
+            buf.extend_from_slice(&item.to_le_bytes());
+            Ok(())
+        }
+
+        fn decode(&self, buf: &[u8]) -> io::Result<u32> {
+            buf.try_into()
+                .map(u32::from_le_bytes)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+
+    #[test]
+    fn pipeline_dead_letters_what_it_discards() {
+        let letters = Arc::new(InMemoryDeadLetters::new(16));
+        let (mut tx, rx) = Pipeline::builder(2)
+            .when_full(WhenFull::DropNewest)
+            .dead_letters(letters.clone())
+            .build();
+
+        block_on(async {
+            for i in 0..4u32 {
+                tx.feed(i).await.unwrap();
+            }
+        });
+        drop(rx);
+
+        let letters = letters
+            .drain()
+            .into_iter()
+            .map(|letter| (letter.item, letter.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            letters,
+            [
+                (2, DeadLetterReason::Dropped),
+                (3, DeadLetterReason::Dropped),
+                (0, DeadLetterReason::Abandoned),
+                (1, DeadLetterReason::Abandoned),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn dead_lettering_pipeline_dead_letters_rejections() {
+        let letters = Arc::new(InMemoryDeadLetters::new(16));
+        let (tx, rx) = Pipeline::builder(1)
+            .send_timeout(Duration::from_millis(5))
+            .dead_letters(letters.clone())
+            .build();
+        let mut tx = DeadLetteringPipeline::new(tx);
+
+        tx.send(1).await.unwrap();
+        assert_eq!(tx.send(2).await, Err(ErrorKind::Timeout));
+        drop(rx);
+        assert_eq!(tx.send(3).await, Err(ErrorKind::Closed));
+
+        let letters = letters
+            .drain()
+            .into_iter()
+            .map(|letter| (letter.item, letter.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            letters,
+            [
+                (2, DeadLetterReason::Rejected(ErrorKind::Timeout)),
+                (1, DeadLetterReason::Abandoned),
+                (3, DeadLetterReason::Rejected(ErrorKind::Closed)),
+            ]
+        );
+    }
+
+    #[test]
+    fn in_memory_keeps_the_most_recent() {
+        let letters = InMemoryDeadLetters::new(2);
+        for i in 0..3 {
+            letters.dead_letter(DeadLetter::new(i, DeadLetterReason::Expired));
+        }
+        assert_eq!(letters.overflowed(), 1);
+        let items = letters
+            .snapshot()
+            .into_iter()
+            .map(|l| l.item)
+            .collect::<Vec<_>>();
+        assert_eq!(items, [1, 2]);
+    }
+
+    #[test]
+    fn file_letters_can_be_read_back() {
+        let path = std::env::temp_dir().join(format!("dead-letters-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let letters = FileDeadLetters::open(&path, Le32).unwrap();
+        let rejected = DeadLetter::rejected(PipelineError::Timeout(7));
+        letters.dead_letter(rejected.clone());
+        letters.dead_letter(DeadLetter::new(8, DeadLetterReason::Evicted));
+        drop(letters);
+
+        let read = FileDeadLetters::read(&path, &Le32).unwrap();
+        assert_eq!(read.len(), 2);
+        assert_eq!(read[0].item, 7);
+        assert_eq!(
+            read[0].reason,
+            DeadLetterReason::Rejected(ErrorKind::Timeout)
+        );
+        assert_eq!(read[0].at, rejected.at);
+        assert_eq!(
+            (read[1].item, read[1].reason),
+            (8, DeadLetterReason::Evicted)
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reopening_cuts_off_a_truncated_record() {
+        let path = std::env::temp_dir().join(format!("dead-letters-torn-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let letters = FileDeadLetters::open(&path, Le32).unwrap();
+        letters.dead_letter(DeadLetter::new(1, DeadLetterReason::Expired));
+        letters.dead_letter(DeadLetter::new(2, DeadLetterReason::Expired));
+        drop(letters);
+        // As if the process crashed halfway through writing the second letter.
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+
+        let letters = FileDeadLetters::open(&path, Le32).unwrap();
+        letters.dead_letter(DeadLetter::new(3, DeadLetterReason::Expired));
+        letters.dead_letter(DeadLetter::new(4, DeadLetterReason::Expired));
+        drop(letters);
+
+        let read = FileDeadLetters::read(&path, &Le32).unwrap();
+        let items = read.into_iter().map(|l| l.item).collect::<Vec<_>>();
+        assert_eq!(items, [1, 3, 4]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
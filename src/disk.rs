@@ -0,0 +1,548 @@
+use crate::{ChannelBackend, Pipeline, PipelineError, TokioBackend};
+use futures::{task::Poll, Sink, SinkExt};
+use std::{
+    collections::VecDeque,
+    error, fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::Context,
+};
+
+const SEGMENT_EXTENSION: &str = "seg";
+const CHECKPOINT_FILE: &str = "checkpoint";
+const DEFAULT_MAX_SEGMENT_BYTES: u64 = 8 * 1024 * 1024;
+
+/// Serializes items for a `DiskBuffer`.
+pub trait Codec<T> {
+    fn encode(&self, item: &T, buf: &mut Vec<u8>) -> io::Result<()>;
+
+    fn decode(&self, buf: &[u8]) -> io::Result<T>;
+}
+
+/// Error returned by `DiskBuffer`.
+pub enum DiskBufferError<T> {
+    /// The wrapped `Pipeline` rejected an item.
+    Pipeline(PipelineError<T>),
+    /// An item could not be encoded or written to disk, and is handed back.
+    Write(T, io::Error),
+    /// Buffered items could not be read back from disk.
+    Read(io::Error),
+}
+
+impl<T> fmt::Debug for DiskBufferError<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskBufferError::Pipeline(error) => fmt.debug_tuple("Pipeline").field(error).finish(),
+            DiskBufferError::Write(_, error) => fmt.debug_tuple("Write").field(error).finish(),
+            DiskBufferError::Read(error) => fmt.debug_tuple("Read").field(error).finish(),
+        }
+    }
+}
+
+impl<T> fmt::Display for DiskBufferError<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiskBufferError::Pipeline(error) => error.fmt(fmt),
+            DiskBufferError::Write(_, error) => write!(fmt, "disk buffer write failed: {}", error),
+            DiskBufferError::Read(error) => write!(fmt, "disk buffer read failed: {}", error),
+        }
+    }
+}
+
+impl<T> error::Error for DiskBufferError<T> {}
+
+impl<T> From<PipelineError<T>> for DiskBufferError<T> {
+    fn from(error: PipelineError<T>) -> Self {
+        DiskBufferError::Pipeline(error)
+    }
+}
+
+struct SegmentWriter {
+    id: u64,
+    file: BufWriter<File>,
+    len: u64,
+}
+
+struct SegmentReader {
+    id: u64,
+    file: BufReader<File>,
+    offset: u64,
+}
+
+/// A `Sink` in front of a `Pipeline` that spills items to disk instead of blocking when the
+/// channel is full.
+///
+/// Spilled items are written as length-prefixed records into numbered segment files and fed back
+/// into the channel in order as it drains, ahead of any newer items. Replay progress is
+/// checkpointed, so reopening the same directory after a restart resumes where it left off; items
+/// handed to the channel after the last checkpoint may be replayed twice after a crash.
+///
+/// Replay is driven by `poll_ready`, `poll_flush` and `poll_close`, so a producer that is idle
+/// should flush, as `forward` does whenever its stream is pending.
+pub struct DiskBuffer<T, C, B: ChannelBackend = TokioBackend> {
+    pipeline: Pipeline<T, B>,
+    codec: C,
+    dir: PathBuf,
+    max_segment_bytes: u64,
+    max_bytes: Option<u64>,
+    segments: VecDeque<u64>,
+    next_segment: u64,
+    writer: Option<SegmentWriter>,
+    reader: Option<SegmentReader>,
+    /// Segment and offset up to which items have been handed to the channel.
+    committed: (u64, u64),
+    checkpoint_dirty: bool,
+    /// An item read from disk that is waiting for channel capacity, and the offset after it.
+    replaying: Option<(T, u64)>,
+    disk_bytes: u64,
+    direct: bool,
+    buf: Vec<u8>,
+}
+
+impl<T: Send + 'static, C: Codec<T>, B: ChannelBackend> DiskBuffer<T, C, B> {
+    /// Opens the buffer in `dir`, picking up any items left there by a previous run.
+    pub fn open(pipeline: Pipeline<T, B>, dir: impl AsRef<Path>, codec: C) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let committed = read_checkpoint(&dir)?;
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let id = match path
+                .file_stem()
+                .and_then(|s| s.to_str())
+                .and_then(|s| s.parse().ok())
+            {
+                Some(id) => id,
+                None => continue,
+            };
+            if id < committed.0 {
+                fs::remove_file(&path)?;
+            } else {
+                segments.push(id);
+            }
+        }
+        segments.sort_unstable();
+
+        let mut disk_bytes = 0;
+        for &id in &segments {
+            disk_bytes += fs::metadata(segment_path(&dir, id))?.len();
+        }
+        // The offset only applies to the segment it was taken in. If that segment is gone, the
+        // process stopped between removing it and moving the checkpoint on, and the next
+        // segment hasn't been read from yet.
+        let offset = if segments.first() == Some(&committed.0) {
+            committed.1
+        } else {
+            0
+        };
+        disk_bytes = disk_bytes.saturating_sub(offset);
+
+        Ok(Self {
+            pipeline,
+            codec,
+            next_segment: segments.last().map_or(committed.0, |id| id + 1),
+            committed: (segments.first().copied().unwrap_or(0), offset),
+            segments: segments.into(),
+            dir,
+            max_segment_bytes: DEFAULT_MAX_SEGMENT_BYTES,
+            max_bytes: None,
+            writer: None,
+            reader: None,
+            checkpoint_dirty: false,
+            replaying: None,
+            disk_bytes,
+            direct: false,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Size after which a new segment file is started.
+    pub fn max_segment_bytes(mut self, bytes: u64) -> Self {
+        self.max_segment_bytes = bytes;
+        self
+    }
+
+    /// Caps the space used on disk, after which `poll_ready` waits for the channel again.
+    pub fn max_bytes(mut self, bytes: u64) -> Self {
+        self.max_bytes = Some(bytes);
+        self
+    }
+
+    /// Bytes currently held on disk, including record headers.
+    pub fn disk_bytes(&self) -> u64 {
+        self.disk_bytes
+    }
+
+    fn is_drained(&self) -> bool {
+        self.segments.is_empty() && self.replaying.is_none()
+    }
+
+    fn append(&mut self, item: T) -> Result<(), DiskBufferError<T>> {
+        self.buf.clear();
+        if let Err(error) = self.codec.encode(&item, &mut self.buf) {
+            return Err(DiskBufferError::Write(item, error));
+        }
+        match self.write_record() {
+            Ok(()) => Ok(()),
+            Err(error) => Err(DiskBufferError::Write(item, error)),
+        }
+    }
+
+    fn write_record(&mut self) -> io::Result<()> {
+        let rolling = self
+            .writer
+            .as_ref()
+            .is_none_or(|w| w.len >= self.max_segment_bytes);
+        if rolling {
+            let id = self.next_segment;
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(segment_path(&self.dir, id))?;
+            self.next_segment += 1;
+            self.segments.push_back(id);
+            self.writer = Some(SegmentWriter {
+                id,
+                file: BufWriter::new(file),
+                len: 0,
+            });
+        }
+
+        let writer = self.writer.as_mut().expect("writer was just opened");
+        writer
+            .file
+            .write_all(&(self.buf.len() as u32).to_le_bytes())?;
+        writer.file.write_all(&self.buf)?;
+        let written = 4 + self.buf.len() as u64;
+        writer.len += written;
+        self.disk_bytes += written;
+        Ok(())
+    }
+
+    /// Reads the next record from disk, retiring segments as they are exhausted.
+    fn read_next(&mut self) -> io::Result<Option<(T, u64)>> {
+        loop {
+            let id = match self.segments.front() {
+                Some(&id) => id,
+                None => return Ok(None),
+            };
+
+            if let Some(writer) = self.writer.as_mut().filter(|w| w.id == id) {
+                writer.file.flush()?;
+            }
+            if self.reader.as_ref().is_none_or(|r| r.id != id) {
+                let offset = if self.committed.0 == id {
+                    self.committed.1
+                } else {
+                    0
+                };
+                let mut file = File::open(segment_path(&self.dir, id))?;
+                file.seek(SeekFrom::Start(offset))?;
+                self.reader = Some(SegmentReader {
+                    id,
+                    file: BufReader::new(file),
+                    offset,
+                });
+            }
+
+            let reader = self.reader.as_mut().expect("reader was just opened");
+            if let Some(buf) = read_record(&mut reader.file)? {
+                reader.offset += 4 + buf.len() as u64;
+                self.disk_bytes = self.disk_bytes.saturating_sub(4 + buf.len() as u64);
+                return Ok(Some((self.codec.decode(&buf)?, reader.offset)));
+            }
+
+            // Everything in this segment has been handed to the channel, since we only read
+            // again once the previous item was sent.
+            self.reader = None;
+            if self.writer.as_ref().is_some_and(|w| w.id == id) {
+                self.writer = None;
+            }
+            fs::remove_file(segment_path(&self.dir, id))?;
+            self.segments.pop_front();
+            self.committed = (self.segments.front().copied().unwrap_or(0), 0);
+            self.checkpoint_dirty = true;
+        }
+    }
+
+    /// Moves items from disk into the channel until either runs out.
+    fn poll_replay(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), DiskBufferError<T>>> {
+        let result = loop {
+            if self.replaying.is_none() {
+                match self.read_next() {
+                    Ok(Some(next)) => self.replaying = Some(next),
+                    Ok(None) => break Poll::Ready(Ok(())),
+                    Err(error) => break Poll::Ready(Err(DiskBufferError::Read(error))),
+                }
+            }
+
+            if self.pipeline.poll_ready_unpin(cx).is_pending() {
+                break Poll::Pending;
+            }
+            let (item, offset) = self.replaying.take().expect("replaying item was just read");
+            if let Err(error) = self.pipeline.start_send_unpin(item) {
+                break Poll::Ready(Err(error.into()));
+            }
+            self.committed.1 = offset;
+            self.checkpoint_dirty = true;
+        };
+
+        if let Err(error) = self.checkpoint() {
+            return Poll::Ready(Err(DiskBufferError::Read(error)));
+        }
+        result
+    }
+
+    fn checkpoint(&mut self) -> io::Result<()> {
+        if !self.checkpoint_dirty {
+            return Ok(());
+        }
+        self.checkpoint_dirty = false;
+
+        if self.segments.is_empty() {
+            return match fs::remove_file(self.dir.join(CHECKPOINT_FILE)) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => Err(error),
+                _ => Ok(()),
+            };
+        }
+        write_checkpoint(&self.dir, self.committed)
+    }
+
+    fn flush_writer(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(writer) => writer.file.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<T, C, B> Sink<T> for DiskBuffer<T, C, B>
+where
+    T: Send + Unpin + 'static,
+    C: Codec<T> + Unpin,
+    B: ChannelBackend,
+{
+    type Error = DiskBufferError<T>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let drained = match self.poll_replay(cx) {
+            Poll::Ready(Ok(())) => self.is_drained(),
+            Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+            Poll::Pending => false,
+        };
+
+        if drained && self.pipeline.poll_ready_unpin(cx).is_ready() {
+            self.direct = true;
+            return Poll::Ready(Ok(()));
+        }
+
+        self.direct = false;
+        match self.max_bytes {
+            Some(max) if self.disk_bytes >= max => Poll::Pending,
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        if std::mem::replace(&mut self.direct, false) {
+            self.pipeline.start_send_unpin(item).map_err(Into::into)
+        } else {
+            self.append(item)
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if let Err(error) = self.flush_writer() {
+            return Poll::Ready(Err(DiskBufferError::Read(error)));
+        }
+        futures::ready!(self.poll_replay(cx))?;
+        self.pipeline.poll_flush_unpin(cx).map_err(Into::into)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        futures::ready!(self.as_mut().poll_flush(cx))?;
+        self.pipeline.poll_close_unpin(cx).map_err(Into::into)
+    }
+}
+
+impl<T, C, B: ChannelBackend> Drop for DiskBuffer<T, C, B> {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.as_mut() {
+            let _ = writer.file.flush();
+        }
+        // An item read from disk but never sent is still covered by the checkpoint, so it will be
+        // read again next time.
+        if self.checkpoint_dirty && !self.segments.is_empty() {
+            let _ = write_checkpoint(&self.dir, self.committed);
+        }
+    }
+}
+
+fn segment_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:016}.{}", id, SEGMENT_EXTENSION))
+}
+
+fn write_checkpoint(dir: &Path, (id, offset): (u64, u64)) -> io::Result<()> {
+    let mut bytes = [0; 16];
+    bytes[..8].copy_from_slice(&id.to_le_bytes());
+    bytes[8..].copy_from_slice(&offset.to_le_bytes());
+    fs::write(dir.join(CHECKPOINT_FILE), bytes)
+}
+
+fn read_checkpoint(dir: &Path) -> io::Result<(u64, u64)> {
+    match fs::read(dir.join(CHECKPOINT_FILE)) {
+        Ok(bytes) if bytes.len() == 16 => {
+            let mut id = [0; 8];
+            let mut offset = [0; 8];
+            id.copy_from_slice(&bytes[..8]);
+            offset.copy_from_slice(&bytes[8..]);
+            Ok((u64::from_le_bytes(id), u64::from_le_bytes(offset)))
+        }
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "corrupt disk buffer checkpoint",
+        )),
+        Err(error) if error.kind() == io::ErrorKind::NotFound => Ok((0, 0)),
+        Err(error) => Err(error),
+    }
+}
+
+/// Reads one length-prefixed record, treating a truncated tail as the end of the segment.
+pub(crate) fn read_record(file: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match file.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(error) => return Err(error),
+    }
+    let mut buf = vec![0; u32::from_le_bytes(len) as usize];
+    match file.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(buf)),
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_record, segment_path, write_checkpoint, Codec, DiskBuffer};
+    use crate::Pipeline;
+    use futures::{executor::block_on, FutureExt, SinkExt, StreamExt};
+    use std::{fs::File, io, path::PathBuf};
+
+    struct Utf8;
+
+    impl Codec<String> for Utf8 {
+        fn encode(&self, item: &String, buf: &mut Vec<u8>) -> io::Result<()> {
+            buf.extend_from_slice(item.as_bytes());
+            Ok(())
+        }
+
+        fn decode(&self, buf: &[u8]) -> io::Result<String> {
+            String::from_utf8(buf.to_vec())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("disk-buffer-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn items(range: std::ops::Range<usize>) -> Vec<String> {
+        range.map(|i| i.to_string()).collect()
+    }
+
+    #[test]
+    fn overflow_is_replayed_in_order() {
+        let dir = temp_dir("overflow");
+        let (tx, mut rx) = Pipeline::bounded(2);
+        let mut buffer = DiskBuffer::open(tx, &dir, Utf8)
+            .unwrap()
+            .max_segment_bytes(16);
+
+        block_on(async {
+            for item in items(0..10) {
+                buffer.feed(item).await.unwrap();
+            }
+            assert!(buffer.disk_bytes() > 0);
+
+            let mut received = Vec::new();
+            while received.len() < 10 {
+                let _ = buffer.flush().now_or_never();
+                received.push(rx.next().await.unwrap());
+            }
+            assert_eq!(received, items(0..10));
+        });
+        assert_eq!(buffer.disk_bytes(), 0);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn buffered_items_survive_reopening() {
+        let dir = temp_dir("reopen");
+        let (tx, mut rx) = Pipeline::bounded(2);
+        let mut buffer = DiskBuffer::open(tx, &dir, Utf8)
+            .unwrap()
+            .max_segment_bytes(16);
+        block_on(async {
+            for item in items(0..10) {
+                buffer.feed(item).await.unwrap();
+            }
+        });
+        drop(buffer);
+        let first = block_on(rx.by_ref().take(2).collect::<Vec<_>>());
+        assert_eq!(first, items(0..2));
+
+        let (tx, rx) = Pipeline::bounded(16);
+        let mut buffer = DiskBuffer::open(tx, &dir, Utf8).unwrap();
+        block_on(buffer.close()).unwrap();
+        drop(buffer);
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), items(2..10));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stale_checkpoint_offset_is_ignored_for_a_removed_segment() {
+        let dir = temp_dir("stale-checkpoint");
+        let (tx, _rx) = Pipeline::bounded(1);
+        let mut buffer = DiskBuffer::open(tx, &dir, Utf8)
+            .unwrap()
+            .max_segment_bytes(16);
+        block_on(async {
+            for item in items(0..10) {
+                buffer.feed(item).await.unwrap();
+            }
+        });
+        let segments = buffer.segments.iter().copied().collect::<Vec<_>>();
+        drop(buffer);
+        assert!(segments.len() > 1);
+
+        // As if the process stopped after removing the first segment, but before the checkpoint
+        // moved past it.
+        std::fs::remove_file(segment_path(&dir, segments[0])).unwrap();
+        write_checkpoint(&dir, (segments[0], 10)).unwrap();
+        let mut expected = Vec::new();
+        for &id in &segments[1..] {
+            let mut file = File::open(segment_path(&dir, id)).unwrap();
+            while let Some(buf) = read_record(&mut file).unwrap() {
+                expected.push(String::from_utf8(buf).unwrap());
+            }
+        }
+
+        let (tx, rx) = Pipeline::bounded(16);
+        let mut buffer = DiskBuffer::open(tx, &dir, Utf8).unwrap();
+        block_on(buffer.close()).unwrap();
+        drop(buffer);
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
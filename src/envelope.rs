@@ -0,0 +1,32 @@
+use std::time::{Duration, Instant};
+
+/// An item in transit through a `Pipeline`'s channel, along with what the receiver needs to know
+/// about it.
+pub(crate) struct Envelope<T> {
+    pub(crate) item: T,
+    pub(crate) enqueued_at: Instant,
+    /// What the item was charged against the byte limit, if there is one.
+    pub(crate) bytes: usize,
+    /// When the receiver should discard the item instead of yielding it.
+    pub(crate) deadline: Option<Instant>,
+    /// Position in the channel, when acknowledgements are enabled.
+    pub(crate) sequence: u64,
+}
+
+impl<T> Envelope<T> {
+    pub(crate) fn new(item: T, bytes: usize, max_age: Option<Duration>) -> Self {
+        let enqueued_at = Instant::now();
+        Self {
+            item,
+            enqueued_at,
+            bytes,
+            deadline: max_age.map(|max_age| enqueued_at + max_age),
+            sequence: 0,
+        }
+    }
+
+    pub(crate) fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| deadline <= Instant::now())
+    }
+}
@@ -0,0 +1,113 @@
+use std::{error::Error, fmt};
+
+/// Error returned by `Pipeline` when it cannot accept an item.
+///
+/// Every variant hands the rejected item back so the caller can retry or dead-letter it instead
+/// of losing it.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum PipelineError<T> {
+    /// The receiving half of the channel has been dropped.
+    Closed(T),
+    /// The receiver has begun a graceful shutdown and is only draining reserved items.
+    Shutdown(T),
+    /// The channel had no room for the item.
+    CapacityExceeded(T),
+    /// The item could not be sent before its deadline.
+    Timeout(T),
+}
+
+/// The reason behind a `PipelineError`, without the rejected item.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Closed,
+    Shutdown,
+    CapacityExceeded,
+    Timeout,
+}
+
+impl<T> PipelineError<T> {
+    pub(crate) fn new(kind: ErrorKind, item: T) -> Self {
+        match kind {
+            ErrorKind::Closed => PipelineError::Closed(item),
+            ErrorKind::Shutdown => PipelineError::Shutdown(item),
+            ErrorKind::CapacityExceeded => PipelineError::CapacityExceeded(item),
+            ErrorKind::Timeout => PipelineError::Timeout(item),
+        }
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            PipelineError::Closed(_) => ErrorKind::Closed,
+            PipelineError::Shutdown(_) => ErrorKind::Shutdown,
+            PipelineError::CapacityExceeded(_) => ErrorKind::CapacityExceeded,
+            PipelineError::Timeout(_) => ErrorKind::Timeout,
+        }
+    }
+
+    /// Returns a reference to the item that could not be sent.
+    pub fn get_ref(&self) -> &T {
+        match self {
+            PipelineError::Closed(item)
+            | PipelineError::Shutdown(item)
+            | PipelineError::CapacityExceeded(item)
+            | PipelineError::Timeout(item) => item,
+        }
+    }
+
+    /// Returns the item that could not be sent.
+    pub fn into_inner(self) -> T {
+        match self {
+            PipelineError::Closed(item)
+            | PipelineError::Shutdown(item)
+            | PipelineError::CapacityExceeded(item)
+            | PipelineError::Timeout(item) => item,
+        }
+    }
+
+    /// Applies `f` to the rejected item, keeping the reason.
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> PipelineError<U> {
+        let kind = self.kind();
+        PipelineError::new(kind, f(self.into_inner()))
+    }
+}
+
+impl<T> fmt::Debug for PipelineError<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{:?}(..)", self.kind())
+    }
+}
+
+impl<T> fmt::Display for PipelineError<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.kind().fmt(fmt)
+    }
+}
+
+impl<T> Error for PipelineError<T> {}
+
+impl Error for ErrorKind {}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Closed => write!(fmt, "pipeline closed"),
+            ErrorKind::Shutdown => write!(fmt, "pipeline shutting down"),
+            ErrorKind::CapacityExceeded => write!(fmt, "pipeline capacity exceeded"),
+            ErrorKind::Timeout => write!(fmt, "pipeline send timed out"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorKind, PipelineError};
+
+    #[test]
+    fn error_keeps_item_and_kind() {
+        let error = PipelineError::Timeout(String::from("foo"));
+
+        assert_eq!(error.kind(), ErrorKind::Timeout);
+        assert_eq!(error.to_string(), "pipeline send timed out");
+        assert_eq!(error.map(|s| s.len()), PipelineError::Timeout(3));
+    }
+}
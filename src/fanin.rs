@@ -0,0 +1,142 @@
+use futures::{task::Poll, Stream, StreamExt};
+use std::{pin::Pin, task::Context};
+
+struct Input<T> {
+    id: usize,
+    stream: Pin<Box<dyn Stream<Item = T> + Send>>,
+    weight: usize,
+    taken: usize,
+}
+
+/// A `Stream` merging many inputs, typically `PipelineReceiver`s, into one.
+///
+/// Inputs are polled in turn, each yielding up to its weight in consecutive items before the
+/// next input gets a chance, so a busy input cannot starve the others. The merged stream ends
+/// once every input has ended.
+pub struct FanIn<T> {
+    inputs: Vec<Input<T>>,
+    cursor: usize,
+    next_id: usize,
+}
+
+impl<T> FanIn<T> {
+    pub fn new() -> Self {
+        Self {
+            inputs: Vec::new(),
+            cursor: 0,
+            next_id: 0,
+        }
+    }
+
+    /// Adds an input with weight 1, returning an id for `is_open`.
+    pub fn push<S>(&mut self, input: S) -> usize
+    where
+        S: Stream<Item = T> + Send + 'static,
+    {
+        self.push_weighted(input, 1)
+    }
+
+    /// Adds an input allowed to yield up to `weight` consecutive items per turn.
+    pub fn push_weighted<S>(&mut self, input: S, weight: usize) -> usize
+    where
+        S: Stream<Item = T> + Send + 'static,
+    {
+        assert!(weight > 0, "fan-in weight must be positive");
+        let id = self.next_id;
+        self.next_id += 1;
+        self.inputs.push(Input {
+            id,
+            stream: Box::pin(input),
+            weight,
+            taken: 0,
+        });
+        id
+    }
+
+    /// Returns whether the input with this id has yet to end.
+    pub fn is_open(&self, id: usize) -> bool {
+        self.inputs.iter().any(|input| input.id == id)
+    }
+
+    pub fn open_inputs(&self) -> usize {
+        self.inputs.len()
+    }
+
+    fn advance(&mut self) {
+        self.inputs[self.cursor].taken = 0;
+        self.cursor = (self.cursor + 1) % self.inputs.len();
+    }
+}
+
+impl<T> Default for FanIn<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Stream for FanIn<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut visited = 0;
+        while visited < self.inputs.len() {
+            let cursor = self.cursor;
+            match self.inputs[cursor].stream.poll_next_unpin(cx) {
+                Poll::Ready(Some(item)) => {
+                    let input = &mut self.inputs[cursor];
+                    input.taken += 1;
+                    if input.taken >= input.weight {
+                        self.advance();
+                    }
+                    return Poll::Ready(Some(item));
+                }
+                Poll::Ready(None) => {
+                    self.inputs.remove(cursor);
+                    if self.cursor >= self.inputs.len() {
+                        self.cursor = 0;
+                    }
+                }
+                Poll::Pending => {
+                    self.advance();
+                    visited += 1;
+                }
+            }
+        }
+
+        if self.inputs.is_empty() {
+            Poll::Ready(None)
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FanIn;
+    use futures::{executor::block_on, stream, StreamExt};
+
+    #[test]
+    fn round_robin_across_inputs() {
+        let mut fanin = FanIn::new();
+        fanin.push(stream::iter(vec![1, 2, 3]));
+        fanin.push(stream::iter(vec![10, 20]));
+
+        assert_eq!(block_on(fanin.collect::<Vec<_>>()), vec![1, 10, 2, 20, 3]);
+    }
+
+    #[test]
+    fn weighted_inputs_take_longer_turns() {
+        let mut fanin = FanIn::new();
+        fanin.push_weighted(stream::repeat(1), 3);
+        let cold = fanin.push(stream::iter(vec![2, 2]));
+
+        let items = block_on(fanin.by_ref().take(8).collect::<Vec<_>>());
+        assert_eq!(items, vec![1, 1, 1, 2, 1, 1, 1, 2]);
+        assert!(block_on(fanin.by_ref().take(4).collect::<Vec<_>>())
+            .iter()
+            .all(|&i| i == 1));
+        assert!(!fanin.is_open(cold));
+        assert_eq!(fanin.open_inputs(), 1);
+    }
+}
@@ -0,0 +1,348 @@
+use crate::{ChannelBackend, Pipeline, PipelineError, TokioBackend};
+use futures::{task::Poll, Sink, SinkExt};
+use std::{pin::Pin, task::Context};
+use tokio::sync::mpsc;
+
+/// How many outputs must be ready before a `FanoutPipeline` accepts the next item.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Quorum {
+    /// Every output must be ready; no output ever misses an item.
+    All,
+    /// At least this many outputs must be ready. Outputs that are still busy when the item is
+    /// sent miss it, which is counted in `FanoutPipeline::skipped`.
+    AtLeast(usize),
+}
+
+enum ControlMessage<T, B: ChannelBackend> {
+    Add(String, Pipeline<T, B>),
+    Remove(String),
+}
+
+/// Handle for adding and removing outputs of a `FanoutPipeline` that is owned elsewhere, e.g. by
+/// a running `forward`.
+///
+/// Changes are applied the next time the fanout is polled for readiness, never between a
+/// reservation and the send that uses it.
+pub struct FanoutControl<T, B: ChannelBackend = TokioBackend> {
+    inner: mpsc::UnboundedSender<ControlMessage<T, B>>,
+}
+
+impl<T, B: ChannelBackend> FanoutControl<T, B> {
+    /// Adds an output, replacing any existing output with the same name.
+    pub fn add(&self, name: impl Into<String>, output: Pipeline<T, B>) {
+        // The fanout owns a sender too, so its receiver can't be closed while this one lives.
+        let _ = self.inner.send(ControlMessage::Add(name.into(), output));
+    }
+
+    /// Removes an output. Items already sent to it stay in its channel for its receiver.
+    pub fn remove(&self, name: impl Into<String>) {
+        let _ = self.inner.send(ControlMessage::Remove(name.into()));
+    }
+}
+
+impl<T, B: ChannelBackend> Clone for FanoutControl<T, B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+struct Output<T, B: ChannelBackend> {
+    name: String,
+    pipeline: Pipeline<T, B>,
+    ready: bool,
+}
+
+/// A `Sink` that sends a clone of every item to each of a dynamic set of `Pipeline`s.
+pub struct FanoutPipeline<T, B: ChannelBackend = TokioBackend> {
+    outputs: Vec<Output<T, B>>,
+    quorum: Quorum,
+    control_tx: mpsc::UnboundedSender<ControlMessage<T, B>>,
+    control_rx: mpsc::UnboundedReceiver<ControlMessage<T, B>>,
+    skipped: usize,
+}
+
+impl<T, B: ChannelBackend> FanoutPipeline<T, B> {
+    pub fn new() -> Self {
+        let (control_tx, control_rx) = mpsc::unbounded_channel();
+        Self {
+            outputs: Vec::new(),
+            quorum: Quorum::All,
+            control_tx,
+            control_rx,
+            skipped: 0,
+        }
+    }
+
+    pub fn with_quorum(mut self, quorum: Quorum) -> Self {
+        self.quorum = quorum;
+        self
+    }
+
+    pub fn control(&self) -> FanoutControl<T, B> {
+        FanoutControl {
+            inner: self.control_tx.clone(),
+        }
+    }
+
+    /// Adds an output, returning the output it replaced if the name was already taken.
+    pub fn add(
+        &mut self,
+        name: impl Into<String>,
+        pipeline: Pipeline<T, B>,
+    ) -> Option<Pipeline<T, B>> {
+        let name = name.into();
+        let output = Output {
+            name,
+            pipeline,
+            ready: false,
+        };
+        match self.outputs.iter_mut().find(|o| o.name == output.name) {
+            Some(existing) => Some(std::mem::replace(existing, output).pipeline),
+            None => {
+                self.outputs.push(output);
+                None
+            }
+        }
+    }
+
+    /// Removes an output. Items already sent to it stay in its channel for its receiver.
+    pub fn remove(&mut self, name: &str) -> Option<Pipeline<T, B>> {
+        let index = self.outputs.iter().position(|o| o.name == name)?;
+        Some(self.outputs.remove(index).pipeline)
+    }
+
+    pub fn len(&self) -> usize {
+        self.outputs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outputs.is_empty()
+    }
+
+    /// Number of times an output missed an item because it wasn't ready under a partial quorum.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    fn required(&self) -> usize {
+        match self.quorum {
+            Quorum::All => self.outputs.len(),
+            Quorum::AtLeast(n) => n.min(self.outputs.len()),
+        }
+    }
+
+    fn process_control(&mut self, cx: &mut Context<'_>) {
+        while let Poll::Ready(Some(message)) = self.control_rx.poll_recv(cx) {
+            match message {
+                ControlMessage::Add(name, pipeline) => {
+                    self.add(name, pipeline);
+                }
+                ControlMessage::Remove(name) => {
+                    self.remove(&name);
+                }
+            }
+        }
+    }
+}
+
+impl<T, B: ChannelBackend> Default for FanoutPipeline<T, B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Clone + Send + 'static, B: ChannelBackend> Sink<T> for FanoutPipeline<T, B> {
+    type Error = PipelineError<T>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.process_control(cx);
+
+        let mut ready = 0;
+        for output in self.outputs.iter_mut() {
+            // `Pipeline` reports rejections from `start_send`, so any resolution counts as ready.
+            if !output.ready && output.pipeline.poll_ready_unpin(cx).is_ready() {
+                output.ready = true;
+            }
+            if output.ready && !output.pipeline.is_closed() {
+                ready += 1;
+            }
+        }
+        // Closed outputs will never take another item, so drop them rather than let them make up
+        // the quorum in place of outputs that are only busy.
+        self.outputs.retain(|o| !o.pipeline.is_closed());
+
+        if ready >= self.required() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        if self.outputs.is_empty() {
+            return Err(PipelineError::Closed(item));
+        }
+
+        // Without a prior `poll_ready` nobody is reserved, so offer the item to every output and
+        // let each one accept it only if it has room.
+        if !self.outputs.iter().any(|o| o.ready) {
+            self.outputs.iter_mut().for_each(|o| o.ready = true);
+        }
+        let targets = self.outputs.iter().filter(|o| o.ready).count();
+        self.skipped += self.outputs.len() - targets;
+
+        let mut remaining = targets;
+        let mut item = Some(item);
+        let mut delivered = false;
+        let mut last_error = None;
+        let mut closed = Vec::new();
+        for output in self.outputs.iter_mut() {
+            if !output.ready {
+                continue;
+            }
+            output.ready = false;
+            remaining -= 1;
+            let next = if remaining == 0 {
+                item.take().expect("item is only taken by the last target")
+            } else {
+                item.clone().expect("item is only taken by the last target")
+            };
+
+            match output.pipeline.start_send_unpin(next) {
+                Ok(()) => delivered = true,
+                Err(error) => {
+                    if let PipelineError::Closed(_) | PipelineError::Shutdown(_) = error {
+                        closed.push(output.name.clone());
+                    }
+                    last_error = Some(error);
+                }
+            }
+        }
+
+        self.outputs.retain(|o| !closed.contains(&o.name));
+
+        match last_error {
+            Some(error) if !delivered => Err(error),
+            _ => Ok(()),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let mut pending = false;
+        for output in self.outputs.iter_mut() {
+            match output.pipeline.poll_flush_unpin(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => pending = true,
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let mut pending = false;
+        for output in self.outputs.iter_mut() {
+            match output.pipeline.poll_close_unpin(cx) {
+                Poll::Ready(Ok(())) => {}
+                Poll::Ready(Err(error)) => return Poll::Ready(Err(error)),
+                Poll::Pending => pending = true,
+            }
+        }
+        if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FanoutPipeline, Quorum};
+    use crate::Pipeline;
+    use futures::{executor::block_on, FutureExt, SinkExt, StreamExt};
+
+    #[test]
+    fn every_output_receives_every_item() {
+        let (tx_a, rx_a) = Pipeline::bounded(8);
+        let (tx_b, rx_b) = Pipeline::bounded(8);
+        let mut fanout = FanoutPipeline::new();
+        fanout.add("a", tx_a);
+        fanout.add("b", tx_b);
+
+        block_on(async {
+            fanout.send(1).await.unwrap();
+            fanout.send(2).await.unwrap();
+        });
+        drop(fanout);
+
+        assert_eq!(block_on(rx_a.collect::<Vec<_>>()), vec![1, 2]);
+        assert_eq!(block_on(rx_b.collect::<Vec<_>>()), vec![1, 2]);
+    }
+
+    #[test]
+    fn outputs_can_change_while_forwarding() {
+        let (tx_a, rx_a) = Pipeline::bounded(8);
+        let (tx_b, rx_b) = Pipeline::bounded(8);
+        let mut fanout = FanoutPipeline::new();
+        let control = fanout.control();
+        fanout.add("a", tx_a);
+
+        block_on(async {
+            fanout.send(1).await.unwrap();
+            control.add("b", tx_b);
+            fanout.send(2).await.unwrap();
+            control.remove("a");
+            fanout.send(3).await.unwrap();
+        });
+        drop(fanout);
+
+        assert_eq!(block_on(rx_a.collect::<Vec<_>>()), vec![1, 2]);
+        assert_eq!(block_on(rx_b.collect::<Vec<_>>()), vec![2, 3]);
+    }
+
+    #[test]
+    fn closed_outputs_do_not_count_towards_the_quorum() {
+        let (tx_a, mut rx_a) = Pipeline::bounded(1);
+        let (tx_b, rx_b) = Pipeline::bounded(8);
+        let mut fanout = FanoutPipeline::new().with_quorum(Quorum::AtLeast(1));
+        fanout.add("a", tx_a);
+        fanout.add("b", tx_b);
+        drop(rx_b);
+
+        block_on(fanout.send(1)).unwrap();
+        assert_eq!(fanout.len(), 1);
+        // "a" is full, and the closed "b" no longer stands in for it.
+        assert!(fanout.send(2).now_or_never().is_none());
+
+        assert_eq!(block_on(rx_a.next()), Some(1));
+        block_on(fanout.send(2)).unwrap();
+        drop(fanout);
+        assert_eq!(block_on(rx_a.collect::<Vec<_>>()), vec![2]);
+    }
+
+    #[test]
+    fn partial_quorum_skips_busy_outputs() {
+        let (tx_a, rx_a) = Pipeline::bounded(8);
+        let (tx_b, rx_b) = Pipeline::bounded(1);
+        let mut fanout = FanoutPipeline::new().with_quorum(Quorum::AtLeast(1));
+        fanout.add("a", tx_a);
+        fanout.add("b", tx_b);
+
+        block_on(async {
+            fanout.send(1).await.unwrap();
+            fanout.send(2).await.unwrap();
+        });
+        assert_eq!(fanout.skipped(), 1);
+        drop(fanout);
+
+        assert_eq!(block_on(rx_a.collect::<Vec<_>>()), vec![1, 2]);
+        assert_eq!(block_on(rx_b.collect::<Vec<_>>()), vec![1]);
+    }
+}
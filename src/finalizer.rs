@@ -0,0 +1,205 @@
+use crate::ByteSizeOf;
+use futures::FutureExt;
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::sync::oneshot;
+
+/// What finally happened to a tracked item.
+///
+/// When an item is split across several finalizers, the most severe outcome wins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeliveryStatus {
+    /// The final sink processed the item.
+    Delivered,
+    /// The item was discarded, either explicitly or by being dropped along the way.
+    Dropped,
+    /// The final sink failed to process the item.
+    Errored,
+}
+
+struct Notifier {
+    status: Mutex<DeliveryStatus>,
+    tx: Mutex<Option<oneshot::Sender<DeliveryStatus>>>,
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        if let Some(tx) = self.tx.get_mut().unwrap().take() {
+            let _ = tx.send(*self.status.get_mut().unwrap());
+        }
+    }
+}
+
+/// One share of the responsibility for reporting an item's `DeliveryStatus`.
+///
+/// The producer's `Delivery` resolves once every clone has been finished or dropped. Dropping a
+/// finalizer without finishing it counts as `DeliveryStatus::Dropped`.
+pub struct Finalizer {
+    notifier: Arc<Notifier>,
+    status: Option<DeliveryStatus>,
+}
+
+impl Finalizer {
+    pub fn new() -> (Self, Delivery) {
+        let (tx, rx) = oneshot::channel();
+        let notifier = Notifier {
+            status: Mutex::new(DeliveryStatus::Delivered),
+            tx: Mutex::new(Some(tx)),
+        };
+        let finalizer = Self {
+            notifier: Arc::new(notifier),
+            status: None,
+        };
+        (finalizer, Delivery { inner: rx })
+    }
+
+    pub fn finish(mut self, status: DeliveryStatus) {
+        self.status = Some(status);
+    }
+}
+
+impl Clone for Finalizer {
+    fn clone(&self) -> Self {
+        Self {
+            notifier: Arc::clone(&self.notifier),
+            status: None,
+        }
+    }
+}
+
+impl Drop for Finalizer {
+    fn drop(&mut self) {
+        let status = self.status.unwrap_or(DeliveryStatus::Dropped);
+        let mut aggregate = self.notifier.status.lock().unwrap();
+        *aggregate = (*aggregate).max(status);
+    }
+}
+
+/// Future handed to the producer of a tracked item, resolving with its `DeliveryStatus`.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Delivery {
+    inner: oneshot::Receiver<DeliveryStatus>,
+}
+
+impl Future for Delivery {
+    type Output = DeliveryStatus;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<DeliveryStatus> {
+        // The notifier always reports before its sender goes away.
+        self.inner
+            .poll_unpin(cx)
+            .map(|status| status.unwrap_or(DeliveryStatus::Dropped))
+    }
+}
+
+/// An item carrying the `Finalizer` that reports its fate back to the producer.
+///
+/// Send `Tracked<T>` through a chain of `Pipeline<Tracked<T>>` stages, use `map` to transform it
+/// without losing the finalizer, and call `finish` in the final sink. Items rejected by a
+/// pipeline come back inside the `PipelineError` still tracked, and items discarded anywhere
+/// along the way, including by a `WhenFull` policy, report `DeliveryStatus::Dropped`.
+pub struct Tracked<T> {
+    item: T,
+    finalizer: Finalizer,
+}
+
+impl<T> Tracked<T> {
+    pub fn new(item: T) -> (Self, Delivery) {
+        let (finalizer, delivery) = Finalizer::new();
+        (Self { item, finalizer }, delivery)
+    }
+
+    pub fn with_finalizer(item: T, finalizer: Finalizer) -> Self {
+        Self { item, finalizer }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.item
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.item
+    }
+
+    pub fn map<U, F: FnOnce(T) -> U>(self, f: F) -> Tracked<U> {
+        Tracked {
+            item: f(self.item),
+            finalizer: self.finalizer,
+        }
+    }
+
+    pub fn into_parts(self) -> (T, Finalizer) {
+        (self.item, self.finalizer)
+    }
+
+    /// Reports the item's fate and returns it.
+    pub fn finish(self, status: DeliveryStatus) -> T {
+        self.finalizer.finish(status);
+        self.item
+    }
+}
+
+impl<T: Clone> Clone for Tracked<T> {
+    fn clone(&self) -> Self {
+        Self {
+            item: self.item.clone(),
+            finalizer: self.finalizer.clone(),
+        }
+    }
+}
+
+impl<T: ByteSizeOf> ByteSizeOf for Tracked<T> {
+    fn allocated_bytes(&self) -> usize {
+        self.item.allocated_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DeliveryStatus, Tracked};
+    use crate::Pipeline;
+    use futures::{executor::block_on, SinkExt, StreamExt};
+
+    #[test]
+    fn delivery_resolves_at_final_sink() {
+        let (mut tx_in, mut rx_in) = Pipeline::bounded(4);
+        let (mut tx_out, mut rx_out) = Pipeline::bounded(4);
+        let (item, delivery) = Tracked::new(1);
+
+        block_on(async {
+            tx_in.send(item).await.unwrap();
+            let item = rx_in.next().await.unwrap().map(|i| i * 10);
+            tx_out.send(item).await.unwrap();
+
+            let item = rx_out.next().await.unwrap();
+            assert_eq!(*item.get_ref(), 10);
+            item.finish(DeliveryStatus::Delivered);
+
+            assert_eq!(delivery.await, DeliveryStatus::Delivered);
+        });
+    }
+
+    #[test]
+    fn discarded_items_report_dropped() {
+        let (mut tx, rx) = Pipeline::bounded(4);
+        let (item, delivery) = Tracked::new(1);
+
+        block_on(tx.send(item)).unwrap();
+        drop(rx);
+        assert_eq!(block_on(delivery), DeliveryStatus::Dropped);
+    }
+
+    #[test]
+    fn worst_status_across_clones_wins() {
+        let (item, delivery) = Tracked::new(1);
+        let copy = item.clone();
+
+        copy.finish(DeliveryStatus::Errored);
+        item.finish(DeliveryStatus::Delivered);
+        assert_eq!(block_on(delivery), DeliveryStatus::Errored);
+    }
+}
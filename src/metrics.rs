@@ -0,0 +1,231 @@
+use crate::ErrorKind;
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+/// Receives measurements from a `Pipeline` and its `PipelineReceiver`.
+///
+/// Every method defaults to doing nothing, so implementations only need to handle what they care
+/// about. Methods are called inline on the send and receive paths and should be cheap.
+pub trait MetricsRecorder: Send + Sync {
+    /// An item was accepted into the channel.
+    fn item_sent(&self) {}
+
+    /// An item was handed back to the producer inside a `PipelineError`.
+    fn item_rejected(&self, _kind: ErrorKind) {}
+
+    /// An item was discarded by the `WhenFull` policy.
+    fn item_dropped(&self) {}
+
+    /// An item was discarded by the receiver because its deadline had passed.
+    fn item_expired(&self) {}
+
+    /// A producer waited this long in `poll_ready` before it could proceed.
+    fn ready_pending(&self, _duration: Duration) {}
+
+    /// The number of items in the channel changed.
+    fn buffer_utilization(&self, _queued: usize, _capacity: usize) {}
+
+    /// An item spent this long between being sent and being received.
+    fn latency(&self, _duration: Duration) {}
+}
+
+#[derive(Debug)]
+pub(crate) struct NoopRecorder;
+
+impl MetricsRecorder for NoopRecorder {}
+
+const BUCKETS: usize = 32;
+
+/// A histogram of durations with power-of-two microsecond buckets.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub fn record(&self, duration: Duration) {
+        let micros = duration.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = ((u64::BITS - micros.leading_zeros()) as usize).min(BUCKETS - 1);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    pub fn sum(&self) -> Duration {
+        Duration::from_micros(self.sum_micros.load(Ordering::Relaxed))
+    }
+
+    /// Returns each bucket's upper bound along with how many durations fell into it.
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        self.buckets
+            .iter()
+            .enumerate()
+            .map(|(i, count)| (Self::upper_bound(i), count.load(Ordering::Relaxed)))
+            .collect()
+    }
+
+    /// Returns the upper bound of the bucket containing the `q`th quantile.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let count = self.count();
+        if count == 0 {
+            return None;
+        }
+        let target = ((count as f64 * q).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= target {
+                return Some(Self::upper_bound(i));
+            }
+        }
+        Some(Self::upper_bound(BUCKETS - 1))
+    }
+
+    fn upper_bound(bucket: usize) -> Duration {
+        Duration::from_micros((1u64 << bucket) - 1)
+    }
+}
+
+impl fmt::Debug for Histogram {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Histogram")
+            .field("count", &self.count())
+            .field("sum", &self.sum())
+            .finish()
+    }
+}
+
+/// A `MetricsRecorder` keeping everything in memory, mainly for tests.
+#[derive(Debug, Default)]
+pub struct InMemoryRecorder {
+    sent: AtomicU64,
+    rejected: AtomicU64,
+    dropped: AtomicU64,
+    expired: AtomicU64,
+    queued: AtomicUsize,
+    capacity: AtomicUsize,
+    ready_pending: Histogram,
+    latency: Histogram,
+}
+
+impl InMemoryRecorder {
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn expired(&self) -> u64 {
+        self.expired.load(Ordering::Relaxed)
+    }
+
+    /// Items in the channel as of the last change.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Fraction of the channel's capacity in use as of the last change.
+    pub fn utilization(&self) -> f64 {
+        match self.capacity.load(Ordering::Relaxed) {
+            0 => 0.0,
+            capacity => self.queued() as f64 / capacity as f64,
+        }
+    }
+
+    pub fn ready_pending(&self) -> &Histogram {
+        &self.ready_pending
+    }
+
+    pub fn latency(&self) -> &Histogram {
+        &self.latency
+    }
+}
+
+impl MetricsRecorder for InMemoryRecorder {
+    fn item_sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn item_rejected(&self, _kind: ErrorKind) {
+        self.rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn item_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn item_expired(&self) {
+        self.expired.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn ready_pending(&self, duration: Duration) {
+        self.ready_pending.record(duration);
+    }
+
+    fn buffer_utilization(&self, queued: usize, capacity: usize) {
+        self.queued.store(queued, Ordering::Relaxed);
+        self.capacity.store(capacity, Ordering::Relaxed);
+    }
+
+    fn latency(&self, duration: Duration) {
+        self.latency.record(duration);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Histogram, InMemoryRecorder};
+    use crate::Pipeline;
+    use futures::{executor::block_on, SinkExt, StreamExt};
+    use std::{sync::Arc, time::Duration};
+
+    #[test]
+    fn histogram_quantiles_use_bucket_bounds() {
+        let histogram = Histogram::default();
+        for micros in [1, 2, 3, 100] {
+            histogram.record(Duration::from_micros(micros));
+        }
+
+        assert_eq!(histogram.count(), 4);
+        assert_eq!(histogram.sum(), Duration::from_micros(106));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(3)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_micros(127)));
+    }
+
+    #[test]
+    fn pipeline_reports_to_recorder() {
+        let metrics = Arc::new(InMemoryRecorder::default());
+        let (mut tx, mut rx) = Pipeline::builder(4).metrics(metrics.clone()).build();
+
+        block_on(async {
+            tx.send(1).await.unwrap();
+            tx.send(2).await.unwrap();
+            assert_eq!(metrics.queued(), 2);
+            assert_eq!(metrics.utilization(), 0.5);
+
+            rx.next().await.unwrap();
+            drop(rx);
+            assert!(tx.send(3).await.is_err());
+        });
+
+        assert_eq!(metrics.sent(), 2);
+        assert_eq!(metrics.rejected(), 1);
+        assert_eq!(metrics.queued(), 1);
+        assert_eq!(metrics.latency().count(), 1);
+    }
+}
@@ -0,0 +1,251 @@
+//! A small model checker for the shutdown handshake, in the spirit of `loom`.
+//!
+//! A scenario spawns a handful of tasks onto a single-threaded scheduler, which runs it again and
+//! again until every order in which the tasks can be polled has been tried. A task only gives
+//! other tasks a turn where it returns `Pending`, so scenarios put a `yield_now` between the
+//! `Sink` and `Stream` calls whose interleaving they want explored. Exploration is depth first
+//! over the scheduling choices, re-running the scenario from scratch for each path.
+//!
+//! A panic in any task, a run where every unfinished task waits on a wakeup that never comes,
+//! or a panic in the check the scenario returns, fails with the schedule that produced it.
+
+use futures::task::{self, ArcWake};
+use std::{
+    cell::Cell,
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+/// Polls a single task may take before the run is reported as a livelock.
+const MAX_STEPS: usize = 10_000;
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+#[derive(Debug)]
+pub(crate) struct Model {
+    preemption_bound: Option<usize>,
+}
+
+impl Model {
+    pub(crate) fn new() -> Self {
+        Self {
+            preemption_bound: None,
+        }
+    }
+
+    /// Limits how often a runnable task may be switched away from within one run.
+    ///
+    /// Most concurrency bugs need only a couple of preemptions, and the bound keeps the number
+    /// of interleavings polynomial rather than exponential in the length of the scenario.
+    pub(crate) fn preemption_bound(mut self, bound: usize) -> Self {
+        self.preemption_bound = Some(bound);
+        self
+    }
+
+    /// Explores every interleaving of the tasks `scenario` spawns, calling the check it returns
+    /// once they have all finished. Returns the number of interleavings tried.
+    pub(crate) fn check<F, C>(&self, scenario: F) -> usize
+    where
+        F: Fn(&mut Spawner) -> C,
+        C: FnOnce(),
+    {
+        let mut path = Vec::new();
+        let mut runs = 0;
+        loop {
+            runs += 1;
+            self.run(&scenario, &mut path);
+
+            // Move on to the next unexplored choice, deepest first.
+            while let Some((chosen, options)) = path.last_mut() {
+                if *chosen + 1 < *options {
+                    *chosen += 1;
+                    break;
+                }
+                path.pop();
+            }
+            if path.is_empty() {
+                return runs;
+            }
+        }
+    }
+
+    /// Runs the scenario once, following `path` and extending it with first choices past its end.
+    fn run<F, C>(&self, scenario: &F, path: &mut Vec<(usize, usize)>)
+    where
+        F: Fn(&mut Spawner) -> C,
+        C: FnOnce(),
+    {
+        let mut spawner = Spawner { tasks: Vec::new() };
+        let check = scenario(&mut spawner);
+        let mut tasks = spawner
+            .tasks
+            .into_iter()
+            .map(|future| Some((future, Arc::new(Flag(AtomicBool::new(true))))))
+            .collect::<Vec<_>>();
+
+        let mut schedule = Vec::new();
+        let mut depth = 0;
+        let mut current = None;
+        let mut preemptions = 0;
+        loop {
+            let runnable = tasks
+                .iter()
+                .enumerate()
+                .filter(
+                    |(_, task)| matches!(task, Some((_, flag)) if flag.0.load(Ordering::SeqCst)),
+                )
+                .map(|(index, _)| index)
+                .collect::<Vec<_>>();
+            if runnable.is_empty() {
+                if tasks.iter().any(Option::is_some) {
+                    panic!(
+                        "deadlock: no task can make progress after schedule {:?}",
+                        schedule
+                    );
+                }
+                break;
+            }
+            if schedule.len() >= MAX_STEPS * tasks.len() {
+                panic!("livelock: no progress after {} steps", schedule.len());
+            }
+
+            // Continuing the current task comes first, so the first path is the one without
+            // preemptions and the bound only has to prune the others.
+            let mut options = runnable;
+            if let Some(position) = current.and_then(|c| options.iter().position(|&i| i == c)) {
+                options.swap(0, position);
+                if self
+                    .preemption_bound
+                    .is_some_and(|bound| preemptions >= bound)
+                {
+                    options.truncate(1);
+                }
+            }
+            let choice = if options.len() == 1 {
+                0
+            } else {
+                if depth == path.len() {
+                    path.push((0, options.len()));
+                }
+                let (chosen, count) = path[depth];
+                assert_eq!(count, options.len(), "scenario is not deterministic");
+                depth += 1;
+                chosen
+            };
+            let index = options[choice];
+            if choice > 0 && current.is_some_and(|c| options.contains(&c)) {
+                preemptions += 1;
+            }
+            current = Some(index);
+            schedule.push(index);
+
+            let (future, flag) = tasks[index].as_mut().unwrap();
+            flag.0.store(false, Ordering::SeqCst);
+            let waker = task::waker(Arc::clone(flag));
+            let mut cx = Context::from_waker(&waker);
+            match panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(&mut cx))) {
+                Ok(Poll::Ready(())) => tasks[index] = None,
+                Ok(Poll::Pending) => {}
+                Err(payload) => panic!(
+                    "task {} panicked after schedule {:?}: {}",
+                    index,
+                    schedule,
+                    message(&payload)
+                ),
+            }
+        }
+
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(check)) {
+            panic!(
+                "check failed after schedule {:?}: {}",
+                schedule,
+                message(&payload)
+            );
+        }
+    }
+}
+
+/// Collects the tasks of one run of a scenario.
+pub(crate) struct Spawner {
+    tasks: Vec<Task>,
+}
+
+impl Spawner {
+    pub(crate) fn spawn(&mut self, future: impl Future<Output = ()> + 'static) {
+        self.tasks.push(Box::pin(future));
+    }
+}
+
+struct Flag(AtomicBool);
+
+impl ArcWake for Flag {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Lets the scheduler switch to another task here.
+pub(crate) async fn yield_now() {
+    let yielded = Cell::new(false);
+    futures::future::poll_fn(|cx| {
+        if yielded.replace(true) {
+            Poll::Ready(())
+        } else {
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+fn message(payload: &Box<dyn std::any::Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{yield_now, Model};
+    use std::{cell::RefCell, rc::Rc};
+
+    #[test]
+    fn explores_every_interleaving() {
+        let seen = RefCell::new(Vec::new());
+        let runs = Model::new().check(|spawner| {
+            let order = Rc::new(RefCell::new(String::new()));
+            for name in ['a', 'b'] {
+                let order = Rc::clone(&order);
+                spawner.spawn(async move {
+                    order.borrow_mut().push(name);
+                    yield_now().await;
+                    order.borrow_mut().push(name);
+                });
+            }
+            let seen = &seen;
+            move || seen.borrow_mut().push(order.borrow().clone())
+        });
+
+        let mut seen = seen.into_inner();
+        seen.sort();
+        assert_eq!(runs, 6);
+        assert_eq!(seen, ["aabb", "abab", "abba", "baab", "baba", "bbaa"]);
+    }
+
+    #[test]
+    #[should_panic(expected = "deadlock")]
+    fn reports_lost_wakeups() {
+        Model::new().check(|spawner| {
+            spawner.spawn(futures::future::pending());
+            || {}
+        });
+    }
+}
@@ -0,0 +1,292 @@
+use crate::backend::{BackendReceiver, BackendSender, ChannelBackend, ChannelClosed, TrySendError};
+use futures::task::Poll;
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    task::{Context, Waker},
+};
+
+/// A bounded multi-producer, multi-consumer queue in the style of `async-channel`.
+///
+/// Both halves can be cloned; each item goes to exactly one receiver. A `PipelineReceiver` using
+/// this backend can be cloned too, to have several consumers.
+#[derive(Debug)]
+pub enum MpmcBackend {}
+
+impl ChannelBackend for MpmcBackend {
+    type Sender<T> = MpmcSender<T>;
+    type Receiver<T> = MpmcReceiver<T>;
+
+    fn channel<T>(capacity: usize) -> (Self::Sender<T>, Self::Receiver<T>) {
+        channel(capacity)
+    }
+}
+
+/// Creates a bounded MPMC queue.
+pub fn channel<T>(capacity: usize) -> (MpmcSender<T>, MpmcReceiver<T>) {
+    assert!(capacity > 0, "mpmc capacity must be positive");
+    let inner = Arc::new(Mutex::new(State {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        reserved: 0,
+        senders: 1,
+        receivers: 1,
+        closed: false,
+        send_wakers: Vec::new(),
+        recv_wakers: Vec::new(),
+    }));
+    let sender = MpmcSender {
+        inner: Arc::clone(&inner),
+        reserved: false,
+    };
+    (sender, MpmcReceiver { inner })
+}
+
+#[derive(Debug)]
+struct State<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    /// Slots promised to senders by `poll_reserve` but not yet filled.
+    reserved: usize,
+    senders: usize,
+    receivers: usize,
+    closed: bool,
+    send_wakers: Vec<Waker>,
+    recv_wakers: Vec<Waker>,
+}
+
+impl<T> State<T> {
+    fn is_closed(&self) -> bool {
+        self.closed || self.receivers == 0
+    }
+
+    fn has_room(&self) -> bool {
+        self.queue.len() + self.reserved < self.capacity
+    }
+
+    fn push(&mut self, item: T) {
+        self.queue.push_back(item);
+        wake_all(&mut self.recv_wakers);
+    }
+}
+
+fn register(wakers: &mut Vec<Waker>, waker: &Waker) {
+    if !wakers.iter().any(|w| w.will_wake(waker)) {
+        wakers.push(waker.clone());
+    }
+}
+
+fn wake_all(wakers: &mut Vec<Waker>) {
+    for waker in wakers.drain(..) {
+        waker.wake();
+    }
+}
+
+#[derive(Debug)]
+pub struct MpmcSender<T> {
+    inner: Arc<Mutex<State<T>>>,
+    reserved: bool,
+}
+
+impl<T> MpmcSender<T> {
+    fn release(&mut self, state: &mut State<T>) {
+        if self.reserved {
+            self.reserved = false;
+            state.reserved -= 1;
+            wake_all(&mut state.send_wakers);
+        }
+    }
+}
+
+impl<T> BackendSender<T> for MpmcSender<T> {
+    fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), ChannelClosed>> {
+        let inner = Arc::clone(&self.inner);
+        let mut state = inner.lock().unwrap();
+        if state.is_closed() {
+            self.release(&mut state);
+            return Poll::Ready(Err(ChannelClosed));
+        }
+        if self.reserved {
+            return Poll::Ready(Ok(()));
+        }
+        if state.has_room() {
+            state.reserved += 1;
+            self.reserved = true;
+            return Poll::Ready(Ok(()));
+        }
+        register(&mut state.send_wakers, cx.waker());
+        Poll::Pending
+    }
+
+    fn try_send(&mut self, item: T) -> Result<(), TrySendError<T>> {
+        let inner = Arc::clone(&self.inner);
+        let mut state = inner.lock().unwrap();
+        if state.is_closed() {
+            self.release(&mut state);
+            return Err(TrySendError::Closed(item));
+        }
+        if self.reserved {
+            self.reserved = false;
+            state.reserved -= 1;
+        } else if !state.has_room() {
+            return Err(TrySendError::Full(item));
+        }
+        state.push(item);
+        Ok(())
+    }
+}
+
+impl<T> Clone for MpmcSender<T> {
+    fn clone(&self) -> Self {
+        self.inner.lock().unwrap().senders += 1;
+        Self {
+            inner: Arc::clone(&self.inner),
+            reserved: false,
+        }
+    }
+}
+
+impl<T> Drop for MpmcSender<T> {
+    fn drop(&mut self) {
+        let inner = Arc::clone(&self.inner);
+        let mut state = inner.lock().unwrap();
+        self.release(&mut state);
+        state.senders -= 1;
+        if state.senders == 0 {
+            wake_all(&mut state.recv_wakers);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct MpmcReceiver<T> {
+    inner: Arc<Mutex<State<T>>>,
+}
+
+impl<T> MpmcReceiver<T> {
+    fn pop(state: &mut State<T>) -> Option<T> {
+        let item = state.queue.pop_front()?;
+        wake_all(&mut state.send_wakers);
+        Some(item)
+    }
+}
+
+impl<T> BackendReceiver<T> for MpmcReceiver<T> {
+    fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut state = self.inner.lock().unwrap();
+        if let Some(item) = Self::pop(&mut state) {
+            return Poll::Ready(Some(item));
+        }
+        // Nothing can be sent once closed, not even into a reserved slot.
+        if state.senders == 0 || state.closed {
+            return Poll::Ready(None);
+        }
+        register(&mut state.recv_wakers, cx.waker());
+        Poll::Pending
+    }
+
+    fn try_recv(&mut self) -> Option<T> {
+        Self::pop(&mut self.inner.lock().unwrap())
+    }
+
+    fn close(&mut self) {
+        let mut state = self.inner.lock().unwrap();
+        state.closed = true;
+        wake_all(&mut state.send_wakers);
+        wake_all(&mut state.recv_wakers);
+    }
+}
+
+impl<T> Clone for MpmcReceiver<T> {
+    fn clone(&self) -> Self {
+        self.inner.lock().unwrap().receivers += 1;
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> Drop for MpmcReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.inner.lock().unwrap();
+        state.receivers -= 1;
+        if state.receivers == 0 {
+            wake_all(&mut state.send_wakers);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, MpmcBackend};
+    use crate::{
+        backend::{BackendReceiver, BackendSender, ChannelClosed, TrySendError},
+        Pipeline,
+    };
+    use futures::{executor::block_on, future::poll_fn, FutureExt, SinkExt, StreamExt};
+
+    #[test]
+    fn reservations_hold_capacity() {
+        let (mut a, mut rx) = channel(1);
+        let mut b = a.clone();
+
+        block_on(poll_fn(|cx| a.poll_reserve(cx))).unwrap();
+        assert_eq!(b.try_send(1), Err(TrySendError::Full(1)));
+        a.try_send(2).unwrap();
+        assert_eq!(rx.try_recv(), Some(2));
+        b.try_send(3).unwrap();
+        assert_eq!(rx.try_recv(), Some(3));
+    }
+
+    #[test]
+    fn closing_keeps_buffered_items() {
+        let (mut tx, mut rx) = channel(2);
+        let mut other = rx.clone();
+
+        tx.try_send(1).unwrap();
+        rx.close();
+        assert_eq!(
+            block_on(poll_fn(|cx| tx.poll_reserve(cx))),
+            Err(ChannelClosed)
+        );
+        assert_eq!(other.try_recv(), Some(1));
+        // Ended once empty, even though a sender is still around.
+        assert_eq!(block_on(poll_fn(|cx| rx.poll_recv(cx))), None);
+        assert_eq!(block_on(poll_fn(|cx| other.poll_recv(cx))), None);
+        drop(tx);
+    }
+
+    #[test]
+    fn closing_wakes_waiting_receivers() {
+        let (_tx, mut rx) = channel::<u32>(2);
+        let mut other = rx.clone();
+
+        let mut waiting = poll_fn(|cx| other.poll_recv(cx));
+        assert!((&mut waiting).now_or_never().is_none());
+        rx.close();
+        assert_eq!(block_on(waiting), None);
+    }
+
+    #[test]
+    fn pipeline_receivers_share_the_items() {
+        let (mut tx, mut a) = Pipeline::builder(8).backend::<MpmcBackend>().build();
+        let mut b = a.clone();
+
+        block_on(async {
+            for i in 0..4u32 {
+                tx.feed(i).await.unwrap();
+            }
+        });
+        let first = block_on(a.next()).unwrap();
+        let second = block_on(b.next()).unwrap();
+        assert_eq!((first, second), (0, 1));
+
+        // Shutting down ends every clone once the channel is empty.
+        a.begin_shutdown();
+        assert_eq!(block_on(b.next()), Some(2));
+        assert_eq!(block_on(a.next()), Some(3));
+        assert_eq!(block_on(b.next()), None);
+        assert_eq!(block_on(a.next()), None);
+        assert!(block_on(tx.send(4)).is_err());
+    }
+}
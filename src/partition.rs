@@ -0,0 +1,259 @@
+use crate::{ChannelBackend, Pipeline, PipelineError, PipelineReceiver, TokioBackend};
+use futures::{ready, task::Poll, Sink, SinkExt};
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    pin::Pin,
+    sync::Arc,
+    task::Context,
+};
+
+/// Points each shard gets on the hash ring. More points spread keys more evenly.
+const VIRTUAL_NODES: usize = 64;
+
+type KeyFn<T, K> = Arc<dyn Fn(&T) -> K + Send + Sync>;
+
+/// A `Sink` that spreads items over several `Pipeline` shards by a key taken from each item.
+///
+/// Every item with the same key goes to the same shard, so items for one key stay in order while
+/// different keys are handled in parallel, one worker per shard. Keys are placed with consistent
+/// hashing, so adding a shard only moves the keys it takes over, and removing one only moves the
+/// keys it had. Items already sent to a shard stay there, so a key that moves may briefly be
+/// processed by two workers at once.
+///
+/// Shards are added and removed on this sink only, not on its clones. Like `PriorityPipeline`,
+/// `start_send` holds on to the item and the following `poll_ready` or `poll_flush` waits for
+/// room in its shard, and another `start_send` while an item is held is refused with
+/// `PipelineError::CapacityExceeded`.
+pub struct PartitionedPipeline<K, T, B: ChannelBackend = TokioBackend> {
+    /// Indexed by shard id, with `None` for removed shards.
+    shards: Vec<Option<Pipeline<T, B>>>,
+    /// Points on the hash ring and the shard owning each, sorted by point.
+    ring: Vec<(u64, usize)>,
+    key: KeyFn<T, K>,
+    /// An item and the hash of its key, waiting for room in its shard.
+    pending: Option<(u64, T)>,
+}
+
+impl<K, T> PartitionedPipeline<K, T> {
+    /// Creates `shards` channels of `capacity` items each, returning a receiver per shard.
+    pub fn bounded(
+        shards: usize,
+        capacity: usize,
+        key: impl Fn(&T) -> K + Send + Sync + 'static,
+    ) -> (Self, Vec<PipelineReceiver<T>>) {
+        let (senders, receivers) = (0..shards).map(|_| Pipeline::bounded(capacity)).unzip();
+        (Self::new(senders, key), receivers)
+    }
+}
+
+impl<K, T, B: ChannelBackend> PartitionedPipeline<K, T, B> {
+    /// Wraps existing pipelines, which become shards `0` to `shards.len() - 1`.
+    pub fn new(shards: Vec<Pipeline<T, B>>, key: impl Fn(&T) -> K + Send + Sync + 'static) -> Self {
+        assert!(
+            !shards.is_empty(),
+            "a partitioned pipeline needs at least one shard"
+        );
+        let mut partitioned = Self {
+            shards: Vec::new(),
+            ring: Vec::new(),
+            key: Arc::new(key),
+            pending: None,
+        };
+        for shard in shards {
+            partitioned.add_shard(shard);
+        }
+        partitioned
+    }
+
+    /// Adds a shard, returning its id.
+    pub fn add_shard(&mut self, shard: Pipeline<T, B>) -> usize {
+        let id = self.shards.len();
+        self.shards.push(Some(shard));
+        self.ring
+            .extend((0..VIRTUAL_NODES).map(|node| (hash(&(id, node)), id)));
+        self.ring.sort_unstable();
+        id
+    }
+
+    /// Removes a shard, handing back its pipeline. The last shard can't be removed.
+    pub fn remove_shard(&mut self, id: usize) -> Option<Pipeline<T, B>> {
+        if self.shards() == 1 {
+            return None;
+        }
+        let shard = self.shards.get_mut(id)?.take()?;
+        self.ring.retain(|&(_, owner)| owner != id);
+        Some(shard)
+    }
+
+    /// Number of shards in use.
+    pub fn shards(&self) -> usize {
+        self.shards.iter().filter(|shard| shard.is_some()).count()
+    }
+
+    /// Id of the shard owning the point of the ring at or after `hash`.
+    fn owner(&self, hash: u64) -> usize {
+        let index = self.ring.partition_point(|&(point, _)| point < hash);
+        self.ring[index % self.ring.len()].1
+    }
+}
+
+impl<K: Hash, T, B: ChannelBackend> PartitionedPipeline<K, T, B> {
+    /// Id of the shard that items with this key go to.
+    pub fn shard_for(&self, key: &K) -> usize {
+        self.owner(hash(key))
+    }
+}
+
+impl<K, T: Send + 'static, B: ChannelBackend> PartitionedPipeline<K, T, B> {
+    /// Sends the item held by `start_send`, if there is one.
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), PipelineError<T>>> {
+        // Looked up on every poll, since its shard may have been removed in the meantime.
+        let id = match self.pending.as_ref() {
+            Some(&(hash, _)) => self.owner(hash),
+            None => return Poll::Ready(Ok(())),
+        };
+        let shard = self.shards[id]
+            .as_mut()
+            .expect("ring points at a removed shard");
+        ready!(shard.poll_ready_unpin(cx))?;
+        let (_, item) = self.pending.take().unwrap();
+        Poll::Ready(shard.start_send_unpin(item))
+    }
+}
+
+impl<K, T, B> Sink<T> for PartitionedPipeline<K, T, B>
+where
+    K: Hash,
+    T: Send + Unpin + 'static,
+    B: ChannelBackend,
+{
+    type Error = PipelineError<T>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_send_pending(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        if self.pending.is_some() {
+            // Called without `poll_ready`, which would have waited for the held item.
+            return Err(PipelineError::CapacityExceeded(item));
+        }
+        let hash = hash(&(self.key)(&item));
+        self.pending = Some((hash, item));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_send_pending(cx))?;
+        for shard in self.shards.iter_mut().flatten() {
+            ready!(shard.poll_flush_unpin(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_send_pending(cx))?;
+        for shard in self.shards.iter_mut().flatten() {
+            ready!(shard.poll_close_unpin(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<K, T, B: ChannelBackend> Clone for PartitionedPipeline<K, T, B> {
+    fn clone(&self) -> Self {
+        Self {
+            shards: self.shards.clone(),
+            ring: self.ring.clone(),
+            key: Arc::clone(&self.key),
+            pending: None,
+        }
+    }
+}
+
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PartitionedPipeline;
+    use crate::{Pipeline, PipelineError};
+    use futures::{executor::block_on, SinkExt, StreamExt};
+
+    #[test]
+    fn keeps_each_key_on_one_shard_in_order() {
+        let (mut tx, receivers) = PartitionedPipeline::bounded(4, 64, |item: &(u32, u32)| item.0);
+
+        block_on(async {
+            for seq in 0..10 {
+                for key in 0..8 {
+                    tx.send((key, seq)).await.unwrap();
+                }
+            }
+        });
+        let owners = (0..8).map(|key| tx.shard_for(&key)).collect::<Vec<_>>();
+        drop(tx);
+
+        for (id, rx) in receivers.into_iter().enumerate() {
+            let items = block_on(rx.collect::<Vec<_>>());
+            for key in 0..8 {
+                let seqs = items
+                    .iter()
+                    .filter(|item| item.0 == key)
+                    .map(|item| item.1)
+                    .collect::<Vec<_>>();
+                if owners[key as usize] == id {
+                    assert_eq!(seqs, (0..10).collect::<Vec<_>>());
+                } else {
+                    assert!(seqs.is_empty());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn start_send_without_poll_ready_is_refused() {
+        let (mut tx, receivers) = PartitionedPipeline::bounded(1, 8, |item: &u32| *item);
+
+        assert_eq!(tx.start_send_unpin(1), Ok(()));
+        assert_eq!(
+            tx.start_send_unpin(2),
+            Err(PipelineError::CapacityExceeded(2))
+        );
+        block_on(tx.close()).unwrap();
+        drop(tx);
+        let rx = receivers.into_iter().next().unwrap();
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), [1]);
+    }
+
+    #[test]
+    fn changing_shards_moves_few_keys() {
+        let (mut tx, _receivers) = PartitionedPipeline::bounded(4, 1, |item: &u32| *item);
+        let before = (0..1000u32)
+            .map(|key| tx.shard_for(&key))
+            .collect::<Vec<_>>();
+
+        let (shard, _rx) = Pipeline::bounded(1);
+        let added = tx.add_shard(shard);
+        let after = (0..1000u32)
+            .map(|key| tx.shard_for(&key))
+            .collect::<Vec<_>>();
+        let moved = before.iter().zip(&after).filter(|(b, a)| b != a).count();
+        assert!(moved > 0 && moved < 400, "{} keys moved", moved);
+        assert!(before
+            .iter()
+            .zip(&after)
+            .all(|(b, a)| b == a || *a == added));
+
+        assert!(tx.remove_shard(1).is_some());
+        let removed = (0..1000u32)
+            .map(|key| tx.shard_for(&key))
+            .collect::<Vec<_>>();
+        assert!(after.iter().zip(&removed).all(|(a, r)| a == r || *a == 1));
+        assert_eq!(tx.shards(), 4);
+    }
+}
@@ -0,0 +1,763 @@
+use crate::{
+    backend::{BackendReceiver, BackendSender, ChannelBackend, TokioBackend, TrySendError},
+    dead_letter::{DeadLetter, DeadLetterReason, DeadLetterSink},
+    envelope::Envelope,
+    receiver::{PipelineReceiver, SharedReceiver},
+    shutdown::{ByteWaiter, Shared},
+    CancelReady, ErrorKind, PipelineBuilder, PipelineError, WhenFull,
+};
+use futures::{ready, task::Poll, FutureExt, Sink};
+use std::{
+    mem,
+    pin::Pin,
+    sync::Arc,
+    task::Context,
+    time::{Duration, Instant},
+};
+use tokio::time::{delay_for, Delay};
+
+/// A `Sink` feeding a bounded channel.
+///
+/// `poll_ready` obtains a reservation which the following `start_send` consumes. When the
+/// reservation is refused, because the receiver is gone or shutting down, `poll_ready` still
+/// resolves and `start_send` hands the item back inside the returned `PipelineError`.
+///
+/// The channel itself is provided by `B`, tokio's `mpsc` unless another `ChannelBackend` is
+/// chosen through `PipelineBuilder::backend`.
+pub struct Pipeline<T, B: ChannelBackend = TokioBackend> {
+    inner: B::Sender<Envelope<T>>,
+    shared: Arc<Shared>,
+    reservation: Reservation,
+    pending_since: Option<Instant>,
+    /// Sequence number of the last item this pipeline sent, when acknowledgements are enabled.
+    last_sequence: u64,
+    config: SendConfig<T>,
+    evict: Option<SharedReceiver<T, B>>,
+    /// Running while `poll_ready` waits, when there is a send timeout.
+    timer: Option<Delay>,
+    /// An accepted item waiting for room under the byte limit, holding the reservation.
+    parked: Option<Parked<T>>,
+}
+
+// Nothing is pinned structurally, and `parked` is only ever moved out of.
+impl<T, B: ChannelBackend> Unpin for Pipeline<T, B> {}
+
+struct Parked<T> {
+    item: T,
+    waiter: Arc<ByteWaiter>,
+}
+
+/// Sender settings chosen through the `PipelineBuilder`, the same for every clone.
+pub(crate) struct SendConfig<T> {
+    pub(crate) when_full: WhenFull,
+    /// Estimates an item's size when the channel has a byte limit.
+    pub(crate) weigh: Option<fn(&T) -> usize>,
+    pub(crate) send_timeout: Option<Duration>,
+    pub(crate) max_age: Option<Duration>,
+    pub(crate) dead_letters: Option<Arc<dyn DeadLetterSink<T>>>,
+}
+
+impl<T> Clone for SendConfig<T> {
+    fn clone(&self) -> Self {
+        Self {
+            when_full: self.when_full,
+            weigh: self.weigh,
+            send_timeout: self.send_timeout,
+            max_age: self.max_age,
+            dead_letters: self.dead_letters.clone(),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Reservation {
+    /// Nothing is held.
+    Idle,
+    /// Counted against the receiver's shutdown handshake, waiting on channel capacity.
+    Acquiring,
+    /// Holds a slot in the channel for the next `start_send`.
+    Acquired,
+    /// The channel was full, and the next item will be discarded.
+    Dropping,
+    /// The next `start_send` will be refused for this reason.
+    Rejected(ErrorKind),
+}
+
+impl<T: Send + 'static, B: ChannelBackend> Sink<T> for Pipeline<T, B> {
+    type Error = PipelineError<T>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let poll = match self.poll_reserve(cx) {
+            Poll::Ready(()) => {
+                self.timer = None;
+                Poll::Ready(())
+            }
+            Poll::Pending => self.poll_timeout(cx),
+        };
+        match (poll.is_ready(), self.pending_since) {
+            (true, Some(since)) => {
+                self.pending_since = None;
+                self.shared.metrics().ready_pending(since.elapsed());
+            }
+            (false, None) => self.pending_since = Some(Instant::now()),
+            _ => {}
+        }
+        poll.map(Ok)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        if self.parked.is_some() {
+            // Called without `poll_ready`, which would have waited for the parked item.
+            self.shared
+                .metrics()
+                .item_rejected(ErrorKind::CapacityExceeded);
+            return Err(PipelineError::CapacityExceeded(item));
+        }
+        self.timer = None;
+        let result = match mem::replace(&mut self.reservation, Reservation::Idle) {
+            Reservation::Acquired => self.charge_and_send(item),
+            Reservation::Dropping => {
+                self.shared.record_drop();
+                self.dead_letter(item, DeadLetterReason::Dropped);
+                Ok(())
+            }
+            Reservation::Rejected(kind) => Err(PipelineError::new(kind, item)),
+            Reservation::Acquiring => {
+                self.shared.release();
+                self.try_send(item)
+            }
+            Reservation::Idle => self.try_send(item),
+        };
+        if let Err(error) = &result {
+            self.shared.metrics().item_rejected(error.kind());
+        }
+        result
+    }
+
+    /// Sends an item parked on the byte limit first. Then, under `AckMode::None` this resolves
+    /// immediately. Otherwise it waits until every item this pipeline has sent has been
+    /// acknowledged, or until the receiver and every `Acker` are gone.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_parked(cx));
+        self.shared
+            .acks()
+            .poll_acked(self.last_sequence, cx)
+            .map(Ok)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// Creates a bounded channel, the same as `Pipeline::bounded`.
+#[deprecated(note = "use `Pipeline::bounded`")]
+pub fn channel<T>(capacity: usize) -> (Pipeline<T>, PipelineReceiver<T>) {
+    Pipeline::bounded(capacity)
+}
+
+impl<T> Pipeline<T> {
+    /// Creates a bounded channel, the same as `Pipeline::bounded`.
+    ///
+    /// This used to wrap an existing `mpsc::Sender`. A `Pipeline` now needs state shared with
+    /// its `PipelineReceiver`, so the channel is always created along with it.
+    #[deprecated(note = "use `Pipeline::bounded`")]
+    pub fn new(capacity: usize) -> (Self, PipelineReceiver<T>) {
+        Self::bounded(capacity)
+    }
+
+    /// Creates a bounded channel whose receiver supports a lossless shutdown handshake.
+    pub fn bounded(capacity: usize) -> (Self, PipelineReceiver<T>) {
+        Self::builder(capacity).build()
+    }
+
+    pub fn builder(capacity: usize) -> PipelineBuilder<T> {
+        PipelineBuilder::new(capacity)
+    }
+}
+
+impl<T, B: ChannelBackend> Pipeline<T, B> {
+    pub(crate) fn from_parts(
+        inner: B::Sender<Envelope<T>>,
+        shared: Arc<Shared>,
+        config: SendConfig<T>,
+        evict: Option<SharedReceiver<T, B>>,
+    ) -> Self {
+        Self {
+            inner,
+            shared,
+            reservation: Reservation::Idle,
+            pending_since: None,
+            last_sequence: 0,
+            config,
+            evict,
+            timer: None,
+            parked: None,
+        }
+    }
+
+    /// Number of items discarded by the `WhenFull` policy, across every clone of this pipeline.
+    pub fn dropped(&self) -> usize {
+        self.shared.dropped()
+    }
+
+    /// Number of items the receiver discarded because their deadline had passed.
+    pub fn expired(&self) -> usize {
+        self.shared.expired()
+    }
+
+    /// Combined size of the items in the channel, as charged against `PipelineBuilder::max_bytes`.
+    pub fn buffered_bytes(&self) -> usize {
+        self.shared.bytes()
+    }
+
+    /// Whether `poll_ready` found the receiver gone or shut down, so the next item will be refused.
+    pub(crate) fn is_closed(&self) -> bool {
+        matches!(
+            self.reservation,
+            Reservation::Rejected(ErrorKind::Closed | ErrorKind::Shutdown)
+        )
+    }
+
+    /// Drives the reservation towards `Acquired`, `Dropping` or `Rejected`, once any parked item
+    /// has been sent.
+    fn poll_reserve(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        ready!(self.poll_parked(cx));
+        match self.reservation {
+            Reservation::Idle => {
+                if !self.shared.try_reserve() {
+                    self.reservation = Reservation::Rejected(self.shared.rejection());
+                    return Poll::Ready(());
+                }
+                self.reservation = Reservation::Acquiring;
+            }
+            Reservation::Acquiring => {}
+            Reservation::Acquired | Reservation::Dropping | Reservation::Rejected(_) => {
+                return Poll::Ready(())
+            }
+        }
+
+        loop {
+            match self.inner.poll_reserve(cx) {
+                Poll::Ready(Ok(())) => {
+                    self.reservation = Reservation::Acquired;
+                }
+                Poll::Ready(Err(_)) => {
+                    self.release();
+                    self.reservation = Reservation::Rejected(ErrorKind::Closed);
+                }
+                Poll::Pending => match self.config.when_full {
+                    WhenFull::Block => return Poll::Pending,
+                    WhenFull::DropNewest => {
+                        self.release();
+                        self.reservation = Reservation::Dropping;
+                    }
+                    WhenFull::DropOldest => {
+                        if self.evict_oldest() {
+                            continue;
+                        }
+                        // Every slot is reserved by a sender that has yet to send, so there is
+                        // nothing to evict until one of them does.
+                        return Poll::Pending;
+                    }
+                },
+            }
+            return Poll::Ready(());
+        }
+    }
+
+    /// Gives up on a pending reservation once the send timeout has passed.
+    fn poll_timeout(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        let timeout = match self.config.send_timeout {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+        let timer = self.timer.get_or_insert_with(|| delay_for(timeout));
+        ready!(timer.poll_unpin(cx));
+        self.timer = None;
+        if self.parked.is_some() {
+            // `start_send` already accepted a parked item, so it can't be handed back. The
+            // timeout is for the next item, which has yet to start waiting for a reservation.
+            self.force_send_parked();
+            return match self.poll_reserve(cx) {
+                Poll::Ready(()) => Poll::Ready(()),
+                Poll::Pending => self.poll_timeout(cx),
+            };
+        }
+        self.release();
+        self.reservation = Reservation::Rejected(ErrorKind::Timeout);
+        Poll::Ready(())
+    }
+
+    /// Discards the item at the head of the channel, returning whether there was one.
+    fn evict_oldest(&self) -> bool {
+        let evict = match self.evict.as_ref() {
+            Some(evict) => evict,
+            None => return false,
+        };
+        let evicted = match evict.lock().unwrap().try_recv() {
+            Some(envelope) => {
+                self.shared.record_dequeue(envelope.bytes);
+                self.shared.record_drop();
+                // Nobody will ever acknowledge an evicted item, so settle it here.
+                self.shared.acks().settle(envelope.sequence);
+                envelope.item
+            }
+            None => return false,
+        };
+        // Only once the receiver is unlocked, since the sink may be slow.
+        self.dead_letter(evicted, DeadLetterReason::Evicted);
+        true
+    }
+
+    pub(crate) fn dead_letter(&self, item: T, reason: DeadLetterReason) {
+        if let Some(dead_letters) = &self.config.dead_letters {
+            dead_letters.dead_letter(DeadLetter::new(item, reason));
+        }
+    }
+
+    fn weigh(&self, item: &T) -> usize {
+        self.config.weigh.map_or(0, |weigh| weigh(item))
+    }
+
+    /// Charges an item against the byte limit, then sends it using the reservation `start_send`
+    /// just took. If it doesn't fit, `WhenFull` decides: the item is discarded, older items are
+    /// evicted until it fits, or it is parked with the reservation until enough bytes have been
+    /// received.
+    fn charge_and_send(&mut self, item: T) -> Result<(), PipelineError<T>> {
+        let bytes = self.weigh(&item);
+        if bytes == 0 {
+            return self.send_reserved(item, 0);
+        }
+        loop {
+            if self.shared.try_charge(bytes) {
+                return self.send_reserved(item, bytes);
+            }
+            match self.config.when_full {
+                WhenFull::DropNewest => {
+                    self.shared.release();
+                    self.shared.record_drop();
+                    self.dead_letter(item, DeadLetterReason::Dropped);
+                    return Ok(());
+                }
+                WhenFull::DropOldest if self.evict_oldest() => {}
+                WhenFull::Block | WhenFull::DropOldest => {
+                    self.reservation = Reservation::Acquired;
+                    self.parked = Some(Parked {
+                        item,
+                        waiter: self.shared.queue_charge(bytes),
+                    });
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Sends the parked item once its bytes have been charged.
+    fn poll_parked(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        match &self.parked {
+            Some(parked) => ready!(parked.waiter.poll_granted(cx)),
+            None => return Poll::Ready(()),
+        }
+        self.send_parked();
+        Poll::Ready(())
+    }
+
+    /// Sends the parked item over the byte limit, if there is one.
+    fn force_send_parked(&mut self) {
+        if let Some(parked) = &self.parked {
+            if !self.shared.cancel_charge(&parked.waiter) {
+                self.shared.force_charge(parked.waiter.bytes());
+            }
+            self.send_parked();
+        }
+    }
+
+    fn send_parked(&mut self) {
+        let Parked { item, waiter } = self.parked.take().expect("an item is parked");
+        self.reservation = Reservation::Idle;
+        // The item was accepted, so the only way left to report a failure is a dead letter.
+        if let Err(error) = self.send_reserved(item, waiter.bytes()) {
+            let kind = error.kind();
+            self.shared.metrics().item_rejected(kind);
+            self.dead_letter(error.into_inner(), DeadLetterReason::Rejected(kind));
+        }
+    }
+
+    /// Sends an item whose reservation is counted with the receiver, then releases it. Its
+    /// `bytes` must have been charged already.
+    fn send_reserved(&mut self, item: T, bytes: usize) -> Result<(), PipelineError<T>> {
+        // Count the item as queued up front, so the receiver can never see it first.
+        self.shared.record_enqueue();
+        let mut envelope = Envelope::new(item, bytes, self.config.max_age);
+        let result = match self.shared.acks().sequence() {
+            Some(mut sequence) => {
+                envelope.sequence = *sequence + 1;
+                let result = self.inner.try_send(envelope);
+                if result.is_ok() {
+                    *sequence += 1;
+                    self.last_sequence = *sequence;
+                }
+                result
+            }
+            None => self.inner.try_send(envelope),
+        };
+        // Only release once the item is in the channel, so a draining receiver that sees no
+        // outstanding reservations is guaranteed to also see the item.
+        self.shared.release();
+        match result {
+            Ok(()) => {
+                self.shared.metrics().item_sent();
+                Ok(())
+            }
+            Err(error) => {
+                self.shared.record_dequeue(bytes);
+                Err(match error {
+                    TrySendError::Closed(envelope) => PipelineError::Closed(envelope.item),
+                    TrySendError::Full(envelope) => PipelineError::CapacityExceeded(envelope.item),
+                })
+            }
+        }
+    }
+
+    /// Sends an item without a prior `poll_ready`, succeeding only if there happens to be room.
+    fn try_send(&mut self, item: T) -> Result<(), PipelineError<T>> {
+        if self.shared.try_reserve() {
+            let bytes = self.weigh(&item);
+            if !self.shared.try_charge(bytes) {
+                self.shared.release();
+                return Err(PipelineError::CapacityExceeded(item));
+            }
+            self.send_reserved(item, bytes)
+        } else {
+            Err(PipelineError::new(self.shared.rejection(), item))
+        }
+    }
+
+    /// Gives up a reservation that will not be used, if one is held.
+    fn release(&mut self) {
+        if let Reservation::Acquiring | Reservation::Acquired =
+            mem::replace(&mut self.reservation, Reservation::Idle)
+        {
+            self.shared.release();
+        }
+    }
+}
+
+impl<T, B: ChannelBackend> CancelReady for Pipeline<T, B> {
+    /// Releases a reservation `poll_ready` is waiting on or has obtained. An item parked on the
+    /// byte limit keeps its reservation, since it was already accepted.
+    fn cancel_ready(&mut self) {
+        if self.parked.is_none() {
+            self.timer = None;
+            self.release();
+        }
+    }
+}
+
+impl<T, B: ChannelBackend> Clone for Pipeline<T, B> {
+    fn clone(&self) -> Self {
+        Self::from_parts(
+            self.inner.clone(),
+            Arc::clone(&self.shared),
+            self.config.clone(),
+            self.evict.clone(),
+        )
+    }
+}
+
+impl<T, B: ChannelBackend> Drop for Pipeline<T, B> {
+    fn drop(&mut self) {
+        // A parked item was accepted, so it goes out over the byte limit rather than be lost.
+        self.force_send_parked();
+        self.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{AckMode, ByteSizeOf, Pipeline, PipelineError, WhenFull};
+    use futures::FutureExt;
+    use futures::{executor::block_on, SinkExt, StreamExt};
+    use std::{thread, time::Duration};
+
+    #[test]
+    fn closed_receiver_returns_item() {
+        let (mut tx, rx) = Pipeline::bounded(4);
+        drop(rx);
+
+        let error = block_on(tx.send(String::from("foo"))).unwrap_err();
+        assert_eq!(error, PipelineError::Closed(String::from("foo")));
+    }
+
+    #[test]
+    fn start_send_without_reservation_needs_room() {
+        let (mut tx, rx) = Pipeline::bounded(1);
+
+        assert_eq!(tx.start_send_unpin(1), Ok(()));
+        assert_eq!(
+            tx.start_send_unpin(2),
+            Err(PipelineError::CapacityExceeded(2))
+        );
+        drop(tx);
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), vec![1]);
+    }
+
+    #[test]
+    fn drop_newest_discards_incoming_items() {
+        let (mut tx, rx) = Pipeline::builder(2).when_full(WhenFull::DropNewest).build();
+
+        block_on(async {
+            for i in 0..5 {
+                tx.send(i).await.unwrap();
+            }
+        });
+        assert_eq!(tx.dropped(), 3);
+        drop(tx);
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), vec![0, 1]);
+    }
+
+    #[test]
+    fn drop_oldest_keeps_most_recent_items() {
+        let (mut tx, rx) = Pipeline::builder(2).when_full(WhenFull::DropOldest).build();
+
+        block_on(async {
+            for i in 0..5 {
+                tx.send(i).await.unwrap();
+            }
+        });
+        assert_eq!(tx.dropped(), 3);
+        drop(tx);
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), vec![3, 4]);
+    }
+
+    #[test]
+    fn flush_waits_for_receiver() {
+        let (mut tx, mut rx) = Pipeline::builder(4).ack_mode(AckMode::OnReceive).build();
+
+        block_on(tx.feed(1)).unwrap();
+        assert!(tx.flush().now_or_never().is_none());
+        block_on(rx.next()).unwrap();
+        assert_eq!(tx.flush().now_or_never(), Some(Ok(())));
+    }
+
+    #[test]
+    fn flush_waits_for_explicit_acks() {
+        let (mut tx, mut rx) = Pipeline::builder(4).ack_mode(AckMode::Explicit).build();
+        let acker = rx.acker();
+
+        block_on(async {
+            tx.feed(1).await.unwrap();
+            tx.feed(2).await.unwrap();
+            rx.next().await.unwrap();
+            rx.next().await.unwrap();
+        });
+        assert!(tx.flush().now_or_never().is_none());
+        acker.ack(1);
+        assert!(tx.flush().now_or_never().is_none());
+        acker.ack(1);
+        assert_eq!(tx.flush().now_or_never(), Some(Ok(())));
+    }
+
+    #[test]
+    fn evicted_items_settle_only_themselves() {
+        let (mut a, mut rx) = Pipeline::builder(1)
+            .when_full(WhenFull::DropOldest)
+            .ack_mode(AckMode::Explicit)
+            .build();
+        let mut b = a.clone();
+        let acker = rx.acker();
+
+        block_on(async {
+            a.feed(1).await.unwrap();
+            rx.next().await.unwrap();
+            b.feed(2).await.unwrap();
+            // Evicts 2.
+            b.feed(3).await.unwrap();
+        });
+        assert_eq!(a.dropped(), 1);
+        assert!(a.flush().now_or_never().is_none());
+
+        acker.ack(1);
+        assert_eq!(a.flush().now_or_never(), Some(Ok(())));
+        assert!(b.flush().now_or_never().is_none());
+        assert_eq!(block_on(rx.next()), Some(3));
+        acker.ack(1);
+        assert_eq!(b.flush().now_or_never(), Some(Ok(())));
+    }
+
+    #[test]
+    fn flush_gives_up_once_nothing_can_ack() {
+        let (mut tx, mut rx) = Pipeline::builder(4).ack_mode(AckMode::Explicit).build();
+        let acker = rx.acker();
+
+        block_on(tx.feed(1)).unwrap();
+        block_on(tx.feed(2)).unwrap();
+        assert_eq!(block_on(rx.next()), Some(1));
+        drop(rx);
+        assert!(tx.flush().now_or_never().is_none());
+        drop(acker);
+        assert_eq!(tx.flush().now_or_never(), Some(Ok(())));
+
+        // Items abandoned by the receiver can't be acknowledged, even by an `Acker` outliving it.
+        let (mut tx, mut rx) = Pipeline::builder(4).ack_mode(AckMode::Explicit).build();
+        let acker = rx.acker();
+
+        block_on(tx.feed(1)).unwrap();
+        block_on(tx.feed(2)).unwrap();
+        assert_eq!(block_on(rx.next()), Some(1));
+        drop(rx);
+        assert!(tx.flush().now_or_never().is_none());
+        acker.ack(1);
+        assert_eq!(tx.flush().now_or_never(), Some(Ok(())));
+    }
+
+    #[test]
+    fn max_bytes_limits_buffered_size() {
+        let (mut tx, mut rx) = Pipeline::builder(100).max_bytes(64).build();
+        let item = |len| "x".repeat(len);
+        let size = item(0).size_of();
+
+        block_on(tx.send(item(40 - size))).unwrap();
+        assert_eq!(tx.buffered_bytes(), 40);
+        // Accepted, but parked until there is room for it.
+        assert!(tx.send(item(40 - size)).now_or_never().is_none());
+        assert_eq!(tx.buffered_bytes(), 40);
+
+        block_on(rx.next()).unwrap();
+        assert_eq!(tx.buffered_bytes(), 40);
+        assert_eq!(tx.flush().now_or_never(), Some(Ok(())));
+        assert_eq!(block_on(rx.next()), Some(item(40 - size)));
+        assert_eq!(tx.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn max_bytes_holds_across_clones() {
+        let (mut a, mut rx) = Pipeline::builder(100).max_bytes(64).build();
+        let (mut b, mut c) = (a.clone(), a.clone());
+        let item = |len| "x".repeat(len);
+        let size = item(0).size_of();
+
+        block_on(a.send(item(40 - size))).unwrap();
+        assert!(b.send(item(40 - size)).now_or_never().is_none());
+        assert!(c.send(item(40 - size)).now_or_never().is_none());
+        assert_eq!(a.buffered_bytes(), 40);
+
+        // The freed bytes make room for one of the parked items, not both.
+        block_on(rx.next()).unwrap();
+        assert_eq!(a.buffered_bytes(), 40);
+        assert_eq!(b.flush().now_or_never(), Some(Ok(())));
+        assert!(c.flush().now_or_never().is_none());
+
+        block_on(rx.next()).unwrap();
+        assert_eq!(c.flush().now_or_never(), Some(Ok(())));
+        block_on(rx.next()).unwrap();
+        assert_eq!(a.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn max_bytes_applies_when_full_policy() {
+        let (mut tx, rx) = Pipeline::builder(100)
+            .max_bytes(16)
+            .when_full(WhenFull::DropOldest)
+            .build();
+
+        block_on(async {
+            for i in 0..5u64 {
+                tx.send(i).await.unwrap();
+            }
+        });
+        assert_eq!(tx.dropped(), 3);
+        drop(tx);
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), vec![3, 4]);
+    }
+
+    #[tokio::test]
+    async fn send_timeout_hands_item_back() {
+        let (mut tx, mut rx) = Pipeline::builder(1)
+            .send_timeout(Duration::from_millis(10))
+            .build();
+
+        tx.send(1).await.unwrap();
+        assert_eq!(tx.send(2).await, Err(PipelineError::Timeout(2)));
+
+        // The timed out reservation was given up, so room is available again after a receive.
+        rx.next().await.unwrap();
+        tx.send(3).await.unwrap();
+        assert_eq!(rx.next().await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn send_timeout_sends_a_parked_item_over_the_byte_limit() {
+        let (mut tx, mut rx) = Pipeline::builder(100)
+            .max_bytes(64)
+            .send_timeout(Duration::from_millis(10))
+            .build();
+        let item = |len| "x".repeat(len);
+        let size = item(0).size_of();
+
+        tx.feed(item(40 - size)).await.unwrap();
+        // Parked, and still accepted.
+        tx.feed(item(40 - size)).await.unwrap();
+        assert_eq!(tx.buffered_bytes(), 40);
+        // Waiting for the parked item times out, which sends it over the limit rather than fail
+        // this item, which never waited itself.
+        tx.feed(item(30 - size)).await.unwrap();
+        assert_eq!(tx.buffered_bytes(), 80);
+
+        assert_eq!(rx.next().await, Some(item(40 - size)));
+        assert_eq!(rx.next().await, Some(item(40 - size)));
+        tx.flush().await.unwrap();
+        assert_eq!(rx.next().await, Some(item(30 - size)));
+        assert_eq!(tx.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn expired_items_are_discarded_at_dequeue() {
+        let (mut tx, mut rx) = Pipeline::builder(4)
+            .max_age(Duration::from_millis(10))
+            .ack_mode(AckMode::OnReceive)
+            .build();
+
+        block_on(async {
+            tx.feed(1).await.unwrap();
+            tx.feed(2).await.unwrap();
+        });
+        thread::sleep(Duration::from_millis(20));
+        block_on(tx.feed(3)).unwrap();
+
+        assert_eq!(block_on(rx.next()), Some(3));
+        assert_eq!(rx.expired(), 2);
+        // Expired items count as settled, so nothing is left to wait for.
+        assert_eq!(tx.flush().now_or_never(), Some(Ok(())));
+    }
+
+    #[test]
+    fn expired_items_settle_only_themselves() {
+        let (mut a, mut rx) = Pipeline::builder(4)
+            .max_age(Duration::from_millis(10))
+            .ack_mode(AckMode::Explicit)
+            .build();
+        let mut b = a.clone();
+        let acker = rx.acker();
+
+        block_on(async {
+            a.feed(1).await.unwrap();
+            rx.next().await.unwrap();
+            b.feed(2).await.unwrap();
+        });
+        thread::sleep(Duration::from_millis(20));
+        block_on(b.feed(3)).unwrap();
+
+        assert_eq!(block_on(rx.next()), Some(3));
+        assert_eq!(rx.expired(), 1);
+        // 1 was received but never acknowledged.
+        assert!(a.flush().now_or_never().is_none());
+
+        acker.ack(1);
+        assert_eq!(a.flush().now_or_never(), Some(Ok(())));
+        assert!(b.flush().now_or_never().is_none());
+        acker.ack(1);
+        assert_eq!(b.flush().now_or_never(), Some(Ok(())));
+    }
+}
@@ -0,0 +1,314 @@
+use crate::{
+    ChannelBackend, Pipeline, PipelineError, PipelineReceiver, ShutdownHandle, TokioBackend,
+};
+use futures::{ready, task::Poll, Sink, SinkExt, Stream};
+use std::{pin::Pin, sync::Arc, task::Context};
+
+/// Default for `PriorityReceiver::max_starvation`.
+const DEFAULT_MAX_STARVATION: usize = 16;
+
+type Classifier<T> = Arc<dyn Fn(&T) -> usize + Send + Sync>;
+
+/// A `Sink` that routes each item to one of several `Pipeline` lanes, chosen by a classifier.
+///
+/// Lane 0 has the highest priority; a classifier result past the last lane picks the last lane.
+/// Each lane has its own capacity, so a full lane of bulk items doesn't hold up items bound for
+/// another lane from a different clone of this sink.
+///
+/// The lane for an item is only known once it is sent, so `start_send` holds on to the item and
+/// the following `poll_ready` or `poll_flush` waits for room in its lane. A rejection from the
+/// lane is returned from that call. Another `start_send` while an item is held is refused with
+/// `PipelineError::CapacityExceeded`.
+pub struct PriorityPipeline<T, B: ChannelBackend = TokioBackend> {
+    lanes: Vec<Pipeline<T, B>>,
+    classify: Classifier<T>,
+    pending: Option<(usize, T)>,
+}
+
+impl<T> PriorityPipeline<T> {
+    /// Creates `lanes` channels of `capacity` items each, along with a receiver that merges them.
+    pub fn bounded(
+        lanes: usize,
+        capacity: usize,
+        classify: impl Fn(&T) -> usize + Send + Sync + 'static,
+    ) -> (Self, PriorityReceiver<T>) {
+        let (senders, receivers) = (0..lanes).map(|_| Pipeline::bounded(capacity)).unzip();
+        (
+            Self::new(senders, classify),
+            PriorityReceiver::new(receivers),
+        )
+    }
+}
+
+impl<T, B: ChannelBackend> PriorityPipeline<T, B> {
+    /// Wraps existing pipelines, highest priority first, e.g. to configure each lane through
+    /// `Pipeline::builder`.
+    pub fn new(
+        lanes: Vec<Pipeline<T, B>>,
+        classify: impl Fn(&T) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        assert!(
+            !lanes.is_empty(),
+            "a priority pipeline needs at least one lane"
+        );
+        Self {
+            lanes,
+            classify: Arc::new(classify),
+            pending: None,
+        }
+    }
+
+    pub fn lanes(&self) -> usize {
+        self.lanes.len()
+    }
+}
+
+impl<T: Send + 'static, B: ChannelBackend> PriorityPipeline<T, B> {
+    /// Sends the item held by `start_send`, if there is one.
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), PipelineError<T>>> {
+        let lane = match self.pending.as_ref() {
+            Some((lane, _)) => *lane,
+            None => return Poll::Ready(Ok(())),
+        };
+        ready!(self.lanes[lane].poll_ready_unpin(cx))?;
+        let (_, item) = self.pending.take().unwrap();
+        Poll::Ready(self.lanes[lane].start_send_unpin(item))
+    }
+}
+
+impl<T, B> Sink<T> for PriorityPipeline<T, B>
+where
+    T: Send + Unpin + 'static,
+    B: ChannelBackend,
+{
+    type Error = PipelineError<T>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_send_pending(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        if self.pending.is_some() {
+            // Called without `poll_ready`, which would have waited for the held item.
+            return Err(PipelineError::CapacityExceeded(item));
+        }
+        let lane = (self.classify)(&item).min(self.lanes.len() - 1);
+        self.pending = Some((lane, item));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_send_pending(cx))?;
+        for lane in self.lanes.iter_mut() {
+            ready!(lane.poll_flush_unpin(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_send_pending(cx))?;
+        for lane in self.lanes.iter_mut() {
+            ready!(lane.poll_close_unpin(cx))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T, B: ChannelBackend> Clone for PriorityPipeline<T, B> {
+    fn clone(&self) -> Self {
+        Self {
+            lanes: self.lanes.clone(),
+            classify: Arc::clone(&self.classify),
+            pending: None,
+        }
+    }
+}
+
+struct Lane<T, B: ChannelBackend> {
+    receiver: PipelineReceiver<T, B>,
+    /// Whether the lane has an item waiting. It stays in the lane's receiver until it is
+    /// yielded, so it isn't counted as received or lost if this receiver is dropped first.
+    waiting: bool,
+    done: bool,
+    /// Items taken from higher lanes while this one had an item waiting.
+    skipped: usize,
+}
+
+/// Merges the lanes of a `PriorityPipeline`, yielding from the highest priority lane that has
+/// an item.
+///
+/// A lane that has had an item waiting while `max_starvation` items were taken from higher lanes
+/// goes next, so lower lanes keep moving even while higher lanes are never empty. The stream
+/// ends once every lane has ended.
+pub struct PriorityReceiver<T, B: ChannelBackend = TokioBackend> {
+    lanes: Vec<Lane<T, B>>,
+    max_starvation: usize,
+}
+
+impl<T, B: ChannelBackend> PriorityReceiver<T, B> {
+    /// Merges receivers, highest priority first.
+    pub fn new(receivers: Vec<PipelineReceiver<T, B>>) -> Self {
+        let lanes = receivers
+            .into_iter()
+            .map(|receiver| Lane {
+                receiver,
+                waiting: false,
+                done: false,
+                skipped: 0,
+            })
+            .collect();
+        Self {
+            lanes,
+            max_starvation: DEFAULT_MAX_STARVATION,
+        }
+    }
+
+    pub fn max_starvation(mut self, max_starvation: usize) -> Self {
+        self.max_starvation = max_starvation;
+        self
+    }
+
+    /// One handle per lane, highest priority first. Shutting down every lane ends the stream
+    /// once each has been drained.
+    pub fn shutdown_handles(&self) -> Vec<ShutdownHandle> {
+        self.lanes
+            .iter()
+            .map(|lane| lane.receiver.shutdown_handle())
+            .collect()
+    }
+}
+
+impl<T: Unpin, B: ChannelBackend> Stream for PriorityReceiver<T, B> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let max_starvation = self.max_starvation;
+        for lane in self.lanes.iter_mut() {
+            if !lane.waiting && !lane.done {
+                match lane.receiver.poll_peek(cx) {
+                    Poll::Ready(true) => lane.waiting = true,
+                    Poll::Ready(false) => lane.done = true,
+                    Poll::Pending => {}
+                }
+            }
+        }
+
+        let waiting = || self.lanes.iter().enumerate().filter(|(_, l)| l.waiting);
+        let chosen = waiting()
+            .find(|(_, lane)| lane.skipped >= max_starvation)
+            .or_else(|| waiting().next())
+            .map(|(index, _)| index);
+        let chosen = match chosen {
+            Some(chosen) => chosen,
+            None if self.lanes.iter().all(|lane| lane.done) => return Poll::Ready(None),
+            None => return Poll::Pending,
+        };
+
+        for lane in self.lanes[chosen + 1..].iter_mut() {
+            if lane.waiting {
+                lane.skipped += 1;
+            }
+        }
+        let lane = &mut self.lanes[chosen];
+        lane.skipped = 0;
+        lane.waiting = false;
+        Poll::Ready(lane.receiver.take_peeked())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PriorityPipeline, PriorityReceiver};
+    use crate::{DeadLetterReason, InMemoryDeadLetters, Pipeline, PipelineError};
+    use futures::{executor::block_on, FutureExt, SinkExt, StreamExt};
+    use std::sync::Arc;
+
+    #[test]
+    fn start_send_without_poll_ready_is_refused() {
+        let (mut tx, rx) = PriorityPipeline::bounded(2, 8, |_: &u32| 0);
+
+        assert_eq!(tx.start_send_unpin(1), Ok(()));
+        assert_eq!(
+            tx.start_send_unpin(2),
+            Err(PipelineError::CapacityExceeded(2))
+        );
+        block_on(tx.close()).unwrap();
+        drop(tx);
+        assert_eq!(block_on(rx.collect::<Vec<_>>()), [1]);
+    }
+
+    #[test]
+    fn higher_lanes_drain_first() {
+        let (mut tx, rx) = PriorityPipeline::bounded(3, 8, |item: &(usize, u32)| item.0);
+
+        block_on(async {
+            for item in [(2, 0), (1, 1), (2, 2), (0, 3), (1, 4), (5, 5)] {
+                tx.send(item).await.unwrap();
+            }
+        });
+        drop(tx);
+
+        let order = block_on(rx.map(|(_, id)| id).collect::<Vec<_>>());
+        assert_eq!(order, vec![3, 1, 4, 0, 2, 5]);
+    }
+
+    #[test]
+    fn lower_lanes_are_not_starved() {
+        let (mut tx, rx) = PriorityPipeline::bounded(2, 16, |item: &u32| (*item >= 100) as usize);
+        let rx = rx.max_starvation(3);
+
+        block_on(async {
+            for item in (100..103).chain(0..10) {
+                tx.send(item).await.unwrap();
+            }
+        });
+        drop(tx);
+
+        let order = block_on(rx.collect::<Vec<_>>());
+        assert_eq!(order, vec![0, 1, 2, 100, 3, 4, 5, 101, 6, 7, 8, 102, 9]);
+    }
+
+    #[test]
+    fn full_lane_does_not_block_other_lanes() {
+        let (mut bulk, mut rx) =
+            PriorityPipeline::bounded(2, 1, |item: &&str| item.starts_with("bulk") as usize);
+        let mut control = bulk.clone();
+
+        block_on(bulk.send("bulk 1")).unwrap();
+        block_on(bulk.feed("bulk 2")).unwrap();
+        assert!(bulk.flush().now_or_never().is_none());
+
+        block_on(control.send("alert")).unwrap();
+        assert_eq!(block_on(rx.next()), Some("alert"));
+        assert_eq!(block_on(rx.next()), Some("bulk 1"));
+    }
+
+    #[test]
+    fn waiting_items_stay_in_their_lane_until_yielded() {
+        let dead_letters = Arc::new(InMemoryDeadLetters::new(8));
+        let (lanes, receivers) = (0..2)
+            .map(|_| {
+                Pipeline::builder(4)
+                    .dead_letters(dead_letters.clone())
+                    .build()
+            })
+            .unzip();
+        let mut tx = PriorityPipeline::new(lanes, |item: &u32| (*item >= 100) as usize);
+        let mut rx = PriorityReceiver::new(receivers);
+
+        block_on(async {
+            tx.send(1).await.unwrap();
+            tx.send(100).await.unwrap();
+        });
+        assert_eq!(block_on(rx.next()), Some(1));
+
+        // 100 was seen waiting, but not received, so it is abandoned rather than lost.
+        drop(rx);
+        let abandoned = dead_letters
+            .drain()
+            .into_iter()
+            .map(|letter| (letter.item, letter.reason))
+            .collect::<Vec<_>>();
+        assert_eq!(abandoned, [(100, DeadLetterReason::Abandoned)]);
+    }
+}
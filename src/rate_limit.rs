@@ -0,0 +1,267 @@
+use crate::{ChannelBackend, Pipeline, PipelineError, TokioBackend};
+use futures::{ready, task::Poll, FutureExt, Sink, SinkExt};
+use std::{
+    fmt,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Waker},
+    time::{Duration, Instant},
+};
+use tokio::time::{delay_for, Delay};
+
+/// A token bucket shared by every clone of a `RateLimitedPipeline` and its controls.
+struct Bucket {
+    /// Tokens added per second.
+    rate: f64,
+    /// Most tokens the bucket holds, i.e. how many items may go through back to back.
+    burst: f64,
+    tokens: f64,
+    refilled_at: Instant,
+    /// Senders waiting for tokens, woken when the limits change.
+    wakers: Vec<Waker>,
+}
+
+impl Bucket {
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.refilled_at = now;
+    }
+
+    /// Takes a token, or returns how long until one is available. `None` means never, at the
+    /// current rate.
+    fn take(&mut self) -> Result<(), Option<Duration>> {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else if self.rate > 0.0 {
+            Err(Some(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.rate,
+            )))
+        } else {
+            Err(None)
+        }
+    }
+
+    fn register(&mut self, waker: &Waker) {
+        if !self.wakers.iter().any(|w| w.will_wake(waker)) {
+            self.wakers.push(waker.clone());
+        }
+    }
+
+    fn wake_all(&mut self) {
+        for waker in self.wakers.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Handle for changing the limits of a running `RateLimitedPipeline`.
+///
+/// Changes apply to every clone of the pipeline, including senders already waiting for a token.
+#[derive(Clone)]
+pub struct RateLimitControl {
+    bucket: Arc<Mutex<Bucket>>,
+}
+
+impl RateLimitControl {
+    /// Sets how many items per second may go through. A rate of zero holds every sender until it
+    /// is raised again.
+    pub fn set_rate(&self, per_second: f64) {
+        assert!(per_second >= 0.0, "rate must not be negative");
+        let mut bucket = self.bucket.lock().unwrap();
+        // Settle the tokens earned at the old rate before switching.
+        bucket.refill();
+        bucket.rate = per_second;
+        bucket.wake_all();
+    }
+
+    /// Sets how many items may go through back to back after a quiet period.
+    pub fn set_burst(&self, burst: usize) {
+        assert!(burst > 0, "burst must be at least one");
+        let mut bucket = self.bucket.lock().unwrap();
+        bucket.refill();
+        bucket.burst = burst as f64;
+        bucket.tokens = bucket.tokens.min(bucket.burst);
+        bucket.wake_all();
+    }
+
+    pub fn rate(&self) -> f64 {
+        self.bucket.lock().unwrap().rate
+    }
+
+    pub fn burst(&self) -> usize {
+        self.bucket.lock().unwrap().burst as usize
+    }
+}
+
+impl fmt::Debug for RateLimitControl {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bucket = self.bucket.lock().unwrap();
+        fmt.debug_struct("RateLimitControl")
+            .field("rate", &bucket.rate)
+            .field("burst", &bucket.burst)
+            .field("tokens", &bucket.tokens)
+            .finish()
+    }
+}
+
+/// A `Sink` that caps how fast items enter a `Pipeline`, using a token bucket.
+///
+/// `poll_ready` stays pending until a token is available and the pipeline has room. The bucket
+/// starts full, lets `burst` items through back to back and refills at `rate` tokens per second.
+/// Clones share the bucket, so the limit applies to all of them together. Requires a tokio
+/// runtime with the timer enabled.
+pub struct RateLimitedPipeline<T, B: ChannelBackend = TokioBackend> {
+    inner: Pipeline<T, B>,
+    bucket: Arc<Mutex<Bucket>>,
+    has_token: bool,
+    timer: Option<Delay>,
+}
+
+impl<T, B: ChannelBackend> RateLimitedPipeline<T, B> {
+    pub fn new(inner: Pipeline<T, B>, per_second: f64, burst: usize) -> Self {
+        assert!(per_second >= 0.0, "rate must not be negative");
+        assert!(burst > 0, "burst must be at least one");
+        let bucket = Bucket {
+            rate: per_second,
+            burst: burst as f64,
+            tokens: burst as f64,
+            refilled_at: Instant::now(),
+            wakers: Vec::new(),
+        };
+        Self {
+            inner,
+            bucket: Arc::new(Mutex::new(bucket)),
+            has_token: false,
+            timer: None,
+        }
+    }
+
+    pub fn control(&self) -> RateLimitControl {
+        RateLimitControl {
+            bucket: Arc::clone(&self.bucket),
+        }
+    }
+
+    /// Waits until this sender holds a token.
+    fn poll_token(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        while !self.has_token {
+            let wait = {
+                let mut bucket = self.bucket.lock().unwrap();
+                match bucket.take() {
+                    Ok(()) => {
+                        self.has_token = true;
+                        break;
+                    }
+                    Err(wait) => {
+                        // Registered even with a timer, so a change of limits cuts the wait short.
+                        bucket.register(cx.waker());
+                        wait
+                    }
+                }
+            };
+            // The wait is worked out afresh on every poll, since the limits may have changed.
+            self.timer = wait.map(delay_for);
+            match self.timer.as_mut() {
+                Some(timer) => ready!(timer.poll_unpin(cx)),
+                None => return Poll::Pending,
+            }
+        }
+        self.timer = None;
+        Poll::Ready(())
+    }
+}
+
+impl<T, B> Sink<T> for RateLimitedPipeline<T, B>
+where
+    T: Send + 'static,
+    B: ChannelBackend,
+{
+    type Error = PipelineError<T>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_token(cx));
+        self.inner.poll_ready_unpin(cx)
+    }
+
+    /// Without a prior `poll_ready`, fails with `CapacityExceeded` unless a token happens to be
+    /// available.
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        let has_token = std::mem::replace(&mut self.has_token, false);
+        if !has_token && self.bucket.lock().unwrap().take().is_err() {
+            return Err(PipelineError::CapacityExceeded(item));
+        }
+        self.inner.start_send_unpin(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_flush_unpin(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_close_unpin(cx)
+    }
+}
+
+impl<T, B: ChannelBackend> Clone for RateLimitedPipeline<T, B> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            bucket: Arc::clone(&self.bucket),
+            has_token: false,
+            timer: None,
+        }
+    }
+}
+
+impl<T, B: ChannelBackend> Drop for RateLimitedPipeline<T, B> {
+    fn drop(&mut self) {
+        // Hand back a token taken by `poll_ready` but never used.
+        if self.has_token {
+            let mut bucket = self.bucket.lock().unwrap();
+            bucket.tokens = (bucket.tokens + 1.0).min(bucket.burst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RateLimitedPipeline;
+    use crate::Pipeline;
+    use futures::{FutureExt, SinkExt};
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn burst_then_rate() {
+        let (tx, _rx) = Pipeline::bounded(100);
+        let mut tx = RateLimitedPipeline::new(tx, 200.0, 5);
+
+        let start = Instant::now();
+        for i in 0..5 {
+            tx.send(i).await.unwrap();
+        }
+        assert!(start.elapsed() < Duration::from_millis(5));
+        for i in 5..15 {
+            tx.send(i).await.unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(45));
+    }
+
+    #[tokio::test]
+    async fn limits_can_change_while_waiting() {
+        let (tx, _rx) = Pipeline::bounded(100);
+        let mut tx = RateLimitedPipeline::new(tx, 0.0, 1);
+        let control = tx.control();
+
+        tx.send(1).await.unwrap();
+        assert!(tx.send(2).now_or_never().is_none());
+
+        control.set_rate(1000.0);
+        let start = Instant::now();
+        tx.send(2).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,277 @@
+use crate::{
+    backend::{BackendReceiver, ChannelBackend, TokioBackend},
+    dead_letter::{DeadLetter, DeadLetterReason, DeadLetterSink},
+    envelope::Envelope,
+    shutdown::{Shared, ShutdownHandle},
+    Acker,
+};
+use futures::{ready, task::Poll, Stream};
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::Context,
+};
+
+/// The backend receiver, shared with any `Pipeline` that evicts items under `WhenFull::DropOldest`.
+pub(crate) type SharedReceiver<T, B> = Arc<Mutex<<B as ChannelBackend>::Receiver<Envelope<T>>>>;
+
+/// Receiving half of a channel created by `Pipeline::bounded` or `Pipeline::builder`.
+///
+/// The stream ends either when every `Pipeline` has been dropped, or when shutdown was requested
+/// through a `ShutdownHandle` and every reserved item has been yielded.
+///
+/// With a backend whose receiver can be cloned, like `MpmcBackend`, so can this, for several
+/// consumers each getting some of the items. The channel only counts as gone once every clone has
+/// been dropped.
+pub struct PipelineReceiver<T, B: ChannelBackend = TokioBackend> {
+    inner: SharedReceiver<T, B>,
+    shared: Arc<Shared>,
+    dead_letters: Option<Arc<dyn DeadLetterSink<T>>>,
+    /// The next item, taken from the channel by `poll_peek` but not yielded yet.
+    peeked: Option<Envelope<T>>,
+    terminated: bool,
+}
+
+// `peeked` is never pinned, so the receiver is free to move.
+impl<T, B: ChannelBackend> Unpin for PipelineReceiver<T, B> {}
+
+impl<T, B: ChannelBackend> PipelineReceiver<T, B> {
+    pub(crate) fn new(
+        inner: SharedReceiver<T, B>,
+        shared: Arc<Shared>,
+        dead_letters: Option<Arc<dyn DeadLetterSink<T>>>,
+    ) -> Self {
+        Self {
+            inner,
+            shared,
+            dead_letters,
+            peeked: None,
+            terminated: false,
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle::new(Arc::clone(&self.shared))
+    }
+
+    /// Returns a handle for acknowledging items under `AckMode::Explicit`.
+    pub fn acker(&self) -> Acker {
+        Acker::new(Arc::clone(&self.shared))
+    }
+
+    /// Refuses new items from every `Pipeline`, leaving the stream to end once the items already
+    /// accepted have been yielded. The same as shutting down through a `ShutdownHandle`.
+    pub fn begin_shutdown(&self) {
+        self.shared.begin_shutdown();
+    }
+
+    /// Begins shutdown, returning a stream of the items still to be yielded.
+    pub fn drain(&mut self) -> Drain<'_, T, B> {
+        self.begin_shutdown();
+        Drain { receiver: self }
+    }
+
+    /// Whether the stream has ended, after which no `Pipeline` accepts items.
+    pub fn is_drained(&self) -> bool {
+        self.shared.is_drained()
+    }
+
+    /// Number of items discarded because their deadline had passed before they were received.
+    pub fn expired(&self) -> usize {
+        self.shared.expired()
+    }
+
+    fn deliver(&mut self, envelope: Envelope<T>) -> T {
+        self.shared.record_dequeue(envelope.bytes);
+        self.shared.record_delivery();
+        self.shared.acks().received(envelope.sequence);
+        self.shared
+            .metrics()
+            .latency(envelope.enqueued_at.elapsed());
+        envelope.item
+    }
+
+    fn expire(&mut self, envelope: Envelope<T>) {
+        self.shared.record_dequeue(envelope.bytes);
+        self.shared.record_expiry();
+        // Nobody will ever acknowledge an expired item, so settle it here.
+        self.shared.acks().settle(envelope.sequence);
+        if let Some(dead_letters) = &self.dead_letters {
+            dead_letters.dead_letter(DeadLetter::new(envelope.item, DeadLetterReason::Expired));
+        }
+    }
+
+    /// Waits for the next item without yielding it, returning whether there is one. Until it is
+    /// taken by `take_peeked` or `poll_next` it is not counted as received, and it is abandoned
+    /// with the rest of the channel if the receiver is dropped.
+    pub(crate) fn poll_peek(&mut self, cx: &mut Context<'_>) -> Poll<bool> {
+        if self.peeked.is_some() && !self.shared.is_forced() {
+            return Poll::Ready(true);
+        }
+        loop {
+            match ready!(self.poll_envelope(cx)) {
+                Some(envelope) if envelope.is_expired() => self.expire(envelope),
+                Some(envelope) => {
+                    self.peeked = Some(envelope);
+                    return Poll::Ready(true);
+                }
+                None => return Poll::Ready(false),
+            }
+        }
+    }
+
+    /// Yields the item found by `poll_peek`.
+    pub(crate) fn take_peeked(&mut self) -> Option<T> {
+        let envelope = self.peeked.take()?;
+        Some(self.deliver(envelope))
+    }
+
+    fn terminate(&mut self) -> Poll<Option<Envelope<T>>> {
+        self.terminated = true;
+        self.shared.finish();
+        Poll::Ready(None)
+    }
+
+    fn poll_envelope(&mut self, cx: &mut Context<'_>) -> Poll<Option<Envelope<T>>> {
+        if self.terminated {
+            return Poll::Ready(None);
+        }
+        if self.shared.is_forced() {
+            return self.terminate();
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        match inner.poll_recv(cx) {
+            Poll::Ready(Some(envelope)) => return Poll::Ready(Some(envelope)),
+            Poll::Ready(None) => {
+                drop(inner);
+                return self.terminate();
+            }
+            Poll::Pending => {}
+        }
+
+        // Registered before checking, so a shutdown or force stop starting concurrently can't be
+        // missed while the channel is idle.
+        self.shared.register_receiver(cx.waker());
+        if self.shared.is_forced() {
+            drop(inner);
+            return self.terminate();
+        }
+        if self.shared.is_shutdown() && self.shared.reserved() == 0 {
+            // Every reservation has either been sent or released, and no new ones can be
+            // made, so whatever is in the channel now is all that's left. Only close once it
+            // is empty, since closing with a sender mid-send is what trips tokio's
+            // `semaphore.is_idle()` assertion.
+            let result = inner.try_recv();
+            if result.is_none() {
+                inner.close();
+            }
+            drop(inner);
+            return match result {
+                Some(envelope) => Poll::Ready(Some(envelope)),
+                None => self.terminate(),
+            };
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<T, B: ChannelBackend> Stream for PipelineReceiver<T, B> {
+    type Item = T;
+
+    /// Items whose deadline has passed are discarded here, and counted in `expired`.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        if ready!(self.poll_peek(cx)) {
+            Poll::Ready(self.take_peeked())
+        } else {
+            Poll::Ready(None)
+        }
+    }
+}
+
+/// Stream returned by `PipelineReceiver::drain`.
+#[must_use = "streams do nothing unless polled"]
+pub struct Drain<'a, T, B: ChannelBackend = TokioBackend> {
+    receiver: &'a mut PipelineReceiver<T, B>,
+}
+
+impl<T, B: ChannelBackend> Stream for Drain<'_, T, B> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        Pin::new(&mut *self.receiver).poll_next(cx)
+    }
+}
+
+impl<T, B> Clone for PipelineReceiver<T, B>
+where
+    B: ChannelBackend,
+    B::Receiver<Envelope<T>>: Clone,
+{
+    fn clone(&self) -> Self {
+        self.shared.add_receiver();
+        self.shared.acks().add_acker();
+        let inner = self.inner.lock().unwrap().clone();
+        Self {
+            inner: Arc::new(Mutex::new(inner)),
+            shared: Arc::clone(&self.shared),
+            dead_letters: self.dead_letters.clone(),
+            peeked: None,
+            terminated: self.terminated,
+        }
+    }
+}
+
+impl<T, B: ChannelBackend> Drop for PipelineReceiver<T, B> {
+    fn drop(&mut self) {
+        let last = self.shared.remove_receiver();
+        if last && !self.terminated {
+            self.shared.finish();
+        }
+        // Whatever is still in the channel will never be received once the last clone is gone.
+        // Evicting pipelines may keep the channel itself alive, but they can't send into it once
+        // the receiver has finished.
+        let mut abandoned = Vec::new();
+        abandoned.extend(self.peeked.take());
+        if last {
+            let mut inner = self.inner.lock().unwrap();
+            while let Some(envelope) = inner.try_recv() {
+                abandoned.push(envelope);
+            }
+        }
+        // Only once the channel is unlocked, since the sink may be slow.
+        for envelope in abandoned {
+            // Never yielded, so no `Acker` can acknowledge it.
+            self.shared.acks().settle(envelope.sequence);
+            if let Some(dead_letters) = &self.dead_letters {
+                self.shared.record_dequeue(envelope.bytes);
+                dead_letters
+                    .dead_letter(DeadLetter::new(envelope.item, DeadLetterReason::Abandoned));
+            }
+        }
+        self.shared.acks().remove_acker();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Pipeline;
+    use futures::{executor::block_on, SinkExt, StreamExt};
+
+    #[test]
+    fn drain_yields_accepted_items_then_ends() {
+        let (mut tx, mut rx) = Pipeline::bounded(8);
+        block_on(async {
+            for i in 0..3 {
+                tx.feed(i).await.unwrap();
+            }
+        });
+
+        assert!(!rx.is_drained());
+        let drained = block_on(rx.drain().collect::<Vec<_>>());
+        assert_eq!(drained, [0, 1, 2]);
+        assert!(rx.is_drained());
+        assert!(block_on(tx.send(3)).is_err());
+    }
+}
@@ -0,0 +1,434 @@
+use crate::{ErrorKind, PipelineError};
+use futures::{ready, task::Poll, FutureExt, Sink, SinkExt};
+use std::{
+    collections::hash_map::RandomState,
+    error, fmt,
+    hash::{BuildHasher, Hasher},
+    pin::Pin,
+    task::Context,
+    time::{Duration, Instant},
+};
+use tokio::time::{delay_for, Delay};
+
+const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+const DEFAULT_MAX_BACKOFF: Duration = Duration::from_secs(1);
+const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+/// Decides whether an error from the sink wrapped by a `RetrySink` is worth retrying.
+pub trait RetryClassifier<E> {
+    fn is_retriable(&self, error: &E) -> bool;
+}
+
+impl<E, F: Fn(&E) -> bool> RetryClassifier<E> for F {
+    fn is_retriable(&self, error: &E) -> bool {
+        self(error)
+    }
+}
+
+/// Retries a `Pipeline` that was full or timed out, but not one that is closed or shutting down.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PipelineRetries;
+
+impl<T> RetryClassifier<PipelineError<T>> for PipelineRetries {
+    fn is_retriable(&self, error: &PipelineError<T>) -> bool {
+        match error.kind() {
+            ErrorKind::CapacityExceeded | ErrorKind::Timeout => true,
+            ErrorKind::Closed | ErrorKind::Shutdown => false,
+        }
+    }
+}
+
+/// Error returned by `RetrySink` once it has given up on an item, or refused one.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RetryError<E, T> {
+    /// The classifier deemed the error not worth retrying.
+    Fatal(E),
+    /// The last of the errors the item ran into before the attempts or time allowed ran out.
+    Exhausted { attempts: u32, error: E },
+    /// `start_send` was called while an earlier item was still being delivered, without the
+    /// `poll_ready` that would have waited for it. The item is handed back.
+    Busy(T),
+}
+
+impl<E, T> RetryError<E, T> {
+    /// The error from the inner sink, unless the item was refused as `Busy`.
+    pub fn into_inner(self) -> Option<E> {
+        match self {
+            RetryError::Fatal(error) | RetryError::Exhausted { error, .. } => Some(error),
+            RetryError::Busy(_) => None,
+        }
+    }
+}
+
+impl<E: fmt::Display, T> fmt::Display for RetryError<E, T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryError::Fatal(error) => error.fmt(fmt),
+            RetryError::Exhausted { attempts, error } => {
+                write!(fmt, "gave up after {} attempts: {}", attempts, error)
+            }
+            RetryError::Busy(_) => write!(fmt, "still delivering an earlier item"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display, T: fmt::Debug> error::Error for RetryError<E, T> {}
+
+/// The item being delivered, and how its attempts have gone so far.
+struct Attempt<T> {
+    item: T,
+    attempts: u32,
+    started: Instant,
+    /// Whether the current attempt has been handed to the sink and only needs flushing.
+    sent: bool,
+    /// Running while waiting to retry.
+    backoff: Option<Delay>,
+}
+
+/// A `Sink` that retries items its inner sink fails on, with exponential backoff and jitter.
+///
+/// Each item is sent and flushed on its own, so that a failure can be pinned on it, and a
+/// retriable failure sends a copy of it again after a backoff. The backoff starts at
+/// `initial_backoff`, doubles on every retry up to `max_backoff`, and is shortened by a random
+/// fraction of up to `jitter` so that many senders retrying at once spread out. An item is given
+/// up on after `max_attempts` attempts or once `max_elapsed` has passed since its first.
+///
+/// Like `PriorityPipeline`, `start_send` holds on to the item and the following `poll_ready` or
+/// `poll_flush` delivers it, returning the error once it is given up on. Another `start_send`
+/// while an item is held is refused with `RetryError::Busy`. Requires a tokio runtime with the
+/// timer enabled.
+pub struct RetrySink<S, T, C> {
+    inner: S,
+    classifier: C,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    jitter: f64,
+    max_attempts: u32,
+    max_elapsed: Option<Duration>,
+    pending: Option<Attempt<T>>,
+    retries: u64,
+    random: RandomState,
+}
+
+impl<S, T, C> RetrySink<S, T, C> {
+    pub fn new(inner: S, classifier: C) -> Self {
+        Self {
+            inner,
+            classifier,
+            initial_backoff: DEFAULT_INITIAL_BACKOFF,
+            max_backoff: DEFAULT_MAX_BACKOFF,
+            jitter: 0.5,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            max_elapsed: None,
+            pending: None,
+            retries: 0,
+            random: RandomState::new(),
+        }
+    }
+
+    pub fn initial_backoff(mut self, backoff: Duration) -> Self {
+        self.initial_backoff = backoff;
+        self
+    }
+
+    pub fn max_backoff(mut self, backoff: Duration) -> Self {
+        self.max_backoff = backoff;
+        self
+    }
+
+    /// Largest fraction each backoff may be shortened by, between 0 and 1.
+    pub fn jitter(mut self, jitter: f64) -> Self {
+        assert!(
+            (0.0..=1.0).contains(&jitter),
+            "jitter must be between 0 and 1"
+        );
+        self.jitter = jitter;
+        self
+    }
+
+    /// Attempts per item, including the first.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        assert!(attempts > 0, "an item needs at least one attempt");
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// How long after its first attempt an item may still be retried.
+    pub fn max_elapsed(mut self, elapsed: Duration) -> Self {
+        self.max_elapsed = Some(elapsed);
+        self
+    }
+
+    /// Total number of retries made, across every item.
+    pub fn retries(&self) -> u64 {
+        self.retries
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// How long to wait before the given retry, counting from one.
+    fn backoff(&self, retry: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .checked_mul(1 << (retry - 1).min(31))
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff));
+        let mut hasher = self.random.build_hasher();
+        hasher.write_u64(self.retries);
+        let random = hasher.finish() as f64 / u64::MAX as f64;
+        exponential.mul_f64(1.0 - self.jitter * random)
+    }
+}
+
+impl<S, T, C> RetrySink<S, T, C>
+where
+    S: Sink<T> + Unpin,
+    T: Clone,
+    C: RetryClassifier<S::Error>,
+{
+    /// Delivers the item held by `start_send`, if there is one.
+    fn poll_deliver(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), RetryError<S::Error, T>>> {
+        loop {
+            let attempt = match self.pending.as_mut() {
+                Some(attempt) => attempt,
+                None => return Poll::Ready(Ok(())),
+            };
+            if let Some(backoff) = attempt.backoff.as_mut() {
+                ready!(backoff.poll_unpin(cx));
+                attempt.backoff = None;
+            }
+
+            if !attempt.sent {
+                let result = match ready!(self.inner.poll_ready_unpin(cx)) {
+                    Ok(()) => self.inner.start_send_unpin(attempt.item.clone()),
+                    Err(error) => Err(error),
+                };
+                attempt.attempts += 1;
+                match result {
+                    Ok(()) => attempt.sent = true,
+                    Err(error) => {
+                        self.fail(error)?;
+                        continue;
+                    }
+                }
+            }
+            match ready!(self.inner.poll_flush_unpin(cx)) {
+                Ok(()) => {
+                    self.pending = None;
+                    return Poll::Ready(Ok(()));
+                }
+                Err(error) => self.fail(error)?,
+            }
+        }
+    }
+
+    /// Schedules a retry of the pending item, or gives up on it.
+    fn fail(&mut self, error: S::Error) -> Result<(), RetryError<S::Error, T>> {
+        let attempt = self.pending.as_ref().expect("failed without an item");
+        let attempts = attempt.attempts;
+        let out_of_time = self
+            .max_elapsed
+            .is_some_and(|max| attempt.started.elapsed() >= max);
+        if !self.classifier.is_retriable(&error) {
+            self.pending = None;
+            return Err(RetryError::Fatal(error));
+        }
+        if attempts >= self.max_attempts || out_of_time {
+            self.pending = None;
+            return Err(RetryError::Exhausted { attempts, error });
+        }
+
+        let backoff = self.backoff(attempts);
+        self.retries += 1;
+        let attempt = self.pending.as_mut().unwrap();
+        attempt.sent = false;
+        attempt.backoff = Some(delay_for(backoff));
+        Ok(())
+    }
+}
+
+impl<S, T, C> Sink<T> for RetrySink<S, T, C>
+where
+    S: Sink<T> + Unpin,
+    T: Clone + Unpin,
+    C: RetryClassifier<S::Error> + Unpin,
+{
+    type Error = RetryError<S::Error, T>;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_deliver(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: T) -> Result<(), Self::Error> {
+        if self.pending.is_some() {
+            return Err(RetryError::Busy(item));
+        }
+        self.pending = Some(Attempt {
+            item,
+            attempts: 0,
+            started: Instant::now(),
+            sent: false,
+            backoff: None,
+        });
+        Ok(())
+    }
+
+    /// Every item is flushed as part of its delivery, so this only has to finish the last one.
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.poll_deliver(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        ready!(self.poll_deliver(cx))?;
+        self.inner.poll_close_unpin(cx).map_err(RetryError::Fatal)
+    }
+}
+
+impl<S: fmt::Debug, T, C> fmt::Debug for RetrySink<S, T, C> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("RetrySink")
+            .field("inner", &self.inner)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .field("max_attempts", &self.max_attempts)
+            .field("max_elapsed", &self.max_elapsed)
+            .field("retries", &self.retries)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PipelineRetries, RetryError, RetrySink};
+    use crate::{ErrorKind, Pipeline, PipelineError};
+    use futures::{task::Poll, Sink, SinkExt, StreamExt};
+    use std::{
+        pin::Pin,
+        task::Context,
+        time::{Duration, Instant},
+    };
+
+    /// Fails the first `failures` flushes, then records what it was sent.
+    struct Flaky {
+        failures: usize,
+        sent: Vec<u32>,
+        flushed: Vec<u32>,
+    }
+
+    impl Sink<u32> for Flaky {
+        type Error = &'static str;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(mut self: Pin<&mut Self>, item: u32) -> Result<(), Self::Error> {
+            self.sent.push(item);
+            Ok(())
+        }
+
+        fn poll_flush(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Result<(), Self::Error>> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                self.sent.clear();
+                return Poll::Ready(Err("flaky"));
+            }
+            let sent = std::mem::take(&mut self.sent);
+            self.flushed.extend(sent);
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    fn flaky(failures: usize) -> Flaky {
+        Flaky {
+            failures,
+            sent: Vec::new(),
+            flushed: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_with_growing_backoff() {
+        let mut sink = RetrySink::new(flaky(3), |_: &&str| true)
+            .initial_backoff(Duration::from_millis(10))
+            .jitter(0.0);
+
+        let start = Instant::now();
+        sink.send(1).await.unwrap();
+        sink.send(2).await.unwrap();
+        // 10 + 20 + 40 milliseconds of backoff before the first item got through.
+        assert!(start.elapsed() >= Duration::from_millis(70));
+        assert_eq!(sink.retries(), 3);
+        assert_eq!(sink.get_ref().flushed, [1, 2]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_fatal_errors_and_exhausted_budgets() {
+        let mut sink = RetrySink::new(flaky(10), |error: &&str| *error != "flaky")
+            .initial_backoff(Duration::from_millis(1));
+        assert_eq!(sink.send(1).await, Err(RetryError::Fatal("flaky")));
+
+        let mut sink = RetrySink::new(flaky(10), |_: &&str| true)
+            .initial_backoff(Duration::from_millis(1))
+            .max_attempts(3);
+        assert_eq!(
+            sink.send(1).await,
+            Err(RetryError::Exhausted {
+                attempts: 3,
+                error: "flaky"
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn retries_a_full_pipeline() {
+        let (tx, mut rx) = Pipeline::builder(1)
+            .send_timeout(Duration::from_millis(5))
+            .build();
+        let mut sink =
+            RetrySink::new(tx, PipelineRetries).initial_backoff(Duration::from_millis(5));
+
+        sink.send(1).await.unwrap();
+        let receive = tokio::spawn(async move {
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+            rx.next().await;
+            rx
+        });
+        sink.send(2).await.unwrap();
+        assert!(sink.retries() > 0);
+
+        let mut rx = receive.await.unwrap();
+        drop(sink);
+        assert_eq!(rx.next().await, Some(2));
+
+        let (tx, rx) = Pipeline::bounded(1);
+        drop(rx);
+        let mut sink = RetrySink::new(tx, PipelineRetries);
+        let error = sink.send(3).await.unwrap_err();
+        assert!(matches!(error, RetryError::Fatal(ref e) if e.kind() == ErrorKind::Closed));
+        assert_eq!(error.into_inner(), Some(PipelineError::Closed(3)));
+    }
+
+    #[tokio::test]
+    async fn start_send_without_poll_ready_is_refused() {
+        let mut sink = RetrySink::new(flaky(0), |_: &&str| true);
+
+        assert_eq!(sink.start_send_unpin(1), Ok(()));
+        assert_eq!(sink.start_send_unpin(2), Err(RetryError::Busy(2)));
+        sink.flush().await.unwrap();
+        assert_eq!(sink.get_ref().flushed, [1]);
+    }
+}
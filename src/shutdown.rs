@@ -0,0 +1,579 @@
+use crate::{
+    ack::Acks,
+    metrics::{MetricsRecorder, NoopRecorder},
+    AckMode, ErrorKind,
+};
+use futures::task::AtomicWaker;
+use std::{
+    collections::VecDeque,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
+};
+
+/// State shared between every `Pipeline` feeding a channel and its `PipelineReceiver`.
+///
+/// Senders count themselves in `reserved` *before* checking `shutdown`, and the receiver sets
+/// `shutdown` *before* checking `reserved`. With both sides using `SeqCst`, either the sender
+/// observes the shutdown and backs off, or the receiver observes the reservation and keeps
+/// draining until it has been sent or released.
+pub(crate) struct Shared {
+    shutdown: AtomicBool,
+    /// Set by `begin_shutdown` only, whereas `shutdown` is also set when the receiver goes away.
+    requested: AtomicBool,
+    forced: AtomicBool,
+    reserved: AtomicUsize,
+    delivered: AtomicUsize,
+    dropped: AtomicUsize,
+    expired: AtomicUsize,
+    queued: AtomicUsize,
+    capacity: usize,
+    bytes: AtomicUsize,
+    max_bytes: Option<usize>,
+    /// Pipelines holding an item that doesn't fit under `max_bytes` yet, served in order.
+    byte_waiters: Mutex<VecDeque<Arc<ByteWaiter>>>,
+    drained: AtomicBool,
+    /// One per clone of the receiver waiting for shutdown to make progress.
+    rx_wakers: Mutex<Vec<Waker>>,
+    /// Clones of the `PipelineReceiver` still around.
+    receivers: AtomicUsize,
+    drain_wakers: Mutex<Vec<Waker>>,
+    metrics: Arc<dyn MetricsRecorder>,
+    acks: Acks,
+}
+
+impl Shared {
+    pub(crate) fn new(
+        capacity: usize,
+        max_bytes: Option<usize>,
+        metrics: Option<Arc<dyn MetricsRecorder>>,
+        ack_mode: AckMode,
+    ) -> Self {
+        Self {
+            shutdown: AtomicBool::new(false),
+            requested: AtomicBool::new(false),
+            forced: AtomicBool::new(false),
+            reserved: AtomicUsize::new(0),
+            delivered: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            expired: AtomicUsize::new(0),
+            queued: AtomicUsize::new(0),
+            capacity,
+            bytes: AtomicUsize::new(0),
+            max_bytes,
+            byte_waiters: Mutex::new(VecDeque::new()),
+            drained: AtomicBool::new(false),
+            rx_wakers: Mutex::new(Vec::new()),
+            receivers: AtomicUsize::new(1),
+            drain_wakers: Mutex::new(Vec::new()),
+            metrics: metrics.unwrap_or_else(|| Arc::new(NoopRecorder)),
+            acks: Acks::new(ack_mode),
+        }
+    }
+
+    pub(crate) fn acks(&self) -> &Acks {
+        &self.acks
+    }
+
+    pub(crate) fn metrics(&self) -> &dyn MetricsRecorder {
+        &*self.metrics
+    }
+
+    /// Adjusts the number of items in the channel and reports the new utilization.
+    pub(crate) fn record_enqueue(&self) {
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst) + 1;
+        self.metrics.buffer_utilization(queued, self.capacity);
+    }
+
+    /// Takes an item out of the count, along with the bytes it was charged.
+    pub(crate) fn record_dequeue(&self, bytes: usize) {
+        let queued = self.queued.fetch_sub(1, Ordering::SeqCst) - 1;
+        self.metrics.buffer_utilization(queued, self.capacity);
+        if bytes > 0 {
+            self.uncharge(bytes);
+        }
+    }
+
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::SeqCst)
+    }
+
+    /// Whether `bytes` more fit under the byte limit. An item always fits into a channel holding
+    /// no other bytes, or it could never be sent, and anything fits once the receiver is gone, so
+    /// the sender goes on to find the channel closed.
+    fn fits(&self, bytes: usize) -> bool {
+        match self.max_bytes {
+            Some(max_bytes) => {
+                let current = self.bytes();
+                current == 0 || current + bytes <= max_bytes || self.is_drained()
+            }
+            None => true,
+        }
+    }
+
+    /// Charges `bytes` against the byte limit if they fit and no other pipeline is waiting for
+    /// room ahead of them.
+    pub(crate) fn try_charge(&self, bytes: usize) -> bool {
+        // Byte counts only change under this lock, so the check and the charge are atomic.
+        let waiters = self.byte_waiters.lock().unwrap();
+        if !waiters.is_empty() || !self.fits(bytes) {
+            return false;
+        }
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+        true
+    }
+
+    /// Queues for `bytes` of room behind any earlier waiters. They are charged as soon as they
+    /// fit, which the returned waiter reports.
+    pub(crate) fn queue_charge(&self, bytes: usize) -> Arc<ByteWaiter> {
+        let waiter = Arc::new(ByteWaiter {
+            bytes,
+            granted: AtomicBool::new(false),
+            waker: AtomicWaker::new(),
+        });
+        let mut waiters = self.byte_waiters.lock().unwrap();
+        waiters.push_back(Arc::clone(&waiter));
+        self.grant(&mut waiters);
+        waiter
+    }
+
+    /// Stops `waiter` from waiting, returning whether its bytes were charged already.
+    pub(crate) fn cancel_charge(&self, waiter: &Arc<ByteWaiter>) -> bool {
+        let mut waiters = self.byte_waiters.lock().unwrap();
+        if waiter.granted.load(Ordering::SeqCst) {
+            return true;
+        }
+        waiters.retain(|queued| !Arc::ptr_eq(queued, waiter));
+        // Whoever was behind it may fit now.
+        self.grant(&mut waiters);
+        false
+    }
+
+    /// Charges `bytes` whether or not they fit.
+    pub(crate) fn force_charge(&self, bytes: usize) {
+        let _waiters = self.byte_waiters.lock().unwrap();
+        self.bytes.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Gives back charged bytes, and charges as many waiters as the freed room can serve.
+    pub(crate) fn uncharge(&self, bytes: usize) {
+        let mut waiters = self.byte_waiters.lock().unwrap();
+        self.bytes.fetch_sub(bytes, Ordering::SeqCst);
+        self.grant(&mut waiters);
+    }
+
+    /// Charges and wakes waiters in order, stopping at the first that doesn't fit.
+    fn grant(&self, waiters: &mut VecDeque<Arc<ByteWaiter>>) {
+        while let Some(waiter) = waiters.front() {
+            if !self.fits(waiter.bytes) {
+                break;
+            }
+            self.bytes.fetch_add(waiter.bytes, Ordering::SeqCst);
+            waiter.granted.store(true, Ordering::SeqCst);
+            waiter.waker.wake();
+            waiters.pop_front();
+        }
+    }
+
+    /// Registers an outstanding reservation, failing if shutdown has already begun.
+    pub(crate) fn try_reserve(&self) -> bool {
+        self.reserved.fetch_add(1, Ordering::SeqCst);
+        if self.shutdown.load(Ordering::SeqCst) {
+            self.release();
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Releases a reservation, either because its item was sent or because it was abandoned.
+    pub(crate) fn release(&self) {
+        if self.reserved.fetch_sub(1, Ordering::SeqCst) == 1 && self.is_shutdown() {
+            wake_all(&self.rx_wakers);
+        }
+    }
+
+    /// The reason new reservations are refused once `try_reserve` starts failing.
+    ///
+    /// Always `Shutdown` once shutdown was requested, even after the receiver has drained or been
+    /// dropped, so a producer racing the end of a drain sees the same error as one refused
+    /// during it. `Closed` means the receiver went away without a shutdown.
+    pub(crate) fn rejection(&self) -> ErrorKind {
+        if self.requested.load(Ordering::SeqCst) {
+            ErrorKind::Shutdown
+        } else {
+            ErrorKind::Closed
+        }
+    }
+
+    pub(crate) fn reserved(&self) -> usize {
+        self.reserved.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn begin_shutdown(&self) {
+        // A receiver that already went away was closed, not shut down.
+        if !self.is_drained() {
+            self.requested.store(true, Ordering::SeqCst);
+        }
+        if !self.shutdown.swap(true, Ordering::SeqCst) {
+            wake_all(&self.rx_wakers);
+        }
+    }
+
+    pub(crate) fn is_shutdown(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Ends the receiver without draining it, returning how many items are left in the channel.
+    ///
+    /// The receiver stops yielding the next time it is polled, and the items left behind are
+    /// dropped along with it. Producers are refused from now on, and `Shutdown` resolves.
+    pub(crate) fn force_stop(&self) -> usize {
+        self.forced.store(true, Ordering::SeqCst);
+        self.begin_shutdown();
+        let abandoned = self.queued.load(Ordering::SeqCst);
+        self.finish();
+        wake_all(&self.rx_wakers);
+        abandoned
+    }
+
+    pub(crate) fn is_forced(&self) -> bool {
+        self.forced.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn register_receiver(&self, waker: &Waker) {
+        let mut wakers = self.rx_wakers.lock().unwrap();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+
+    pub(crate) fn add_receiver(&self) {
+        self.receivers.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Returns whether that was the last clone of the receiver.
+    pub(crate) fn remove_receiver(&self) -> bool {
+        self.receivers.fetch_sub(1, Ordering::SeqCst) == 1
+    }
+
+    pub(crate) fn record_delivery(&self) {
+        self.delivered.fetch_add(1, Ordering::SeqCst);
+    }
+
+    pub(crate) fn record_drop(&self) {
+        self.dropped.fetch_add(1, Ordering::SeqCst);
+        self.metrics.item_dropped();
+    }
+
+    pub(crate) fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn record_expiry(&self) {
+        self.expired.fetch_add(1, Ordering::SeqCst);
+        self.metrics.item_expired();
+    }
+
+    pub(crate) fn expired(&self) -> usize {
+        self.expired.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn delivered(&self) -> usize {
+        self.delivered.load(Ordering::SeqCst)
+    }
+
+    /// Marks the receiver as fully drained and wakes anyone waiting on `Shutdown`.
+    pub(crate) fn finish(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+        self.drained.store(true, Ordering::SeqCst);
+        wake_all(&self.drain_wakers);
+        // Every waiter fits now, and finds the channel closed.
+        self.grant(&mut self.byte_waiters.lock().unwrap());
+    }
+
+    pub(crate) fn is_drained(&self) -> bool {
+        self.drained.load(Ordering::SeqCst)
+    }
+
+    fn poll_drained(&self, cx: &mut Context<'_>) -> Poll<usize> {
+        if self.is_drained() {
+            return Poll::Ready(self.delivered());
+        }
+
+        let mut wakers = self.drain_wakers.lock().unwrap();
+        // Re-check under the lock so a concurrent `finish` can't slip between the check and the
+        // registration.
+        if self.is_drained() {
+            return Poll::Ready(self.delivered());
+        }
+        if !wakers.iter().any(|w| w.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// A `Pipeline` holding an accepted item until its bytes fit under the byte limit.
+#[derive(Debug)]
+pub(crate) struct ByteWaiter {
+    bytes: usize,
+    granted: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl ByteWaiter {
+    pub(crate) fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// Resolves once the bytes have been charged.
+    pub(crate) fn poll_granted(&self, cx: &mut Context<'_>) -> Poll<()> {
+        self.waker.register(cx.waker());
+        if self.granted.load(Ordering::SeqCst) {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+fn wake_all(wakers: &Mutex<Vec<Waker>>) {
+    let wakers = std::mem::take(&mut *wakers.lock().unwrap());
+    for waker in wakers {
+        waker.wake();
+    }
+}
+
+impl fmt::Debug for Shared {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Shared")
+            .field("shutdown", &self.shutdown)
+            .field("forced", &self.forced)
+            .field("reserved", &self.reserved)
+            .field("delivered", &self.delivered)
+            .field("dropped", &self.dropped)
+            .field("expired", &self.expired)
+            .field("queued", &self.queued)
+            .field("capacity", &self.capacity)
+            .field("bytes", &self.bytes)
+            .field("max_bytes", &self.max_bytes)
+            .field("drained", &self.drained)
+            .field("acks", &self.acks)
+            .finish()
+    }
+}
+
+/// Handle used to gracefully shut down a `PipelineReceiver`.
+///
+/// Once shutdown begins, every `Pipeline` feeding the receiver stops accepting new items, while
+/// items that were already reserved through `poll_ready` are still delivered. The receiver stream
+/// ends once all of those items have been yielded.
+#[derive(Clone, Debug)]
+pub struct ShutdownHandle {
+    shared: Arc<Shared>,
+}
+
+impl ShutdownHandle {
+    pub(crate) fn new(shared: Arc<Shared>) -> Self {
+        Self { shared }
+    }
+
+    /// Begins shutdown and returns a future resolving once the receiver has been drained.
+    pub fn shutdown(&self) -> Shutdown {
+        self.shared.begin_shutdown();
+        Shutdown {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shared.is_shutdown()
+    }
+
+    /// Like `shutdown`, but leaves the receiver to end on its own once every `Pipeline` is gone.
+    pub(crate) fn drained(&self) -> Shutdown {
+        Shutdown {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+
+    pub(crate) fn delivered(&self) -> usize {
+        self.shared.delivered()
+    }
+
+    pub(crate) fn force_stop(&self) -> usize {
+        self.shared.force_stop()
+    }
+}
+
+/// Future returned by `ShutdownHandle::shutdown`.
+///
+/// Resolves with the total number of items the receiver yielded over its lifetime, which is
+/// exactly the number of items accepted by its `Pipeline`s, less any dropped or expired.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Shutdown {
+    shared: Arc<Shared>,
+}
+
+impl Future for Shutdown {
+    type Output = usize;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<usize> {
+        self.shared.poll_drained(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        model::{yield_now, Model},
+        Pipeline, PipelineError,
+    };
+    use futures::{executor::block_on, future::poll_fn, SinkExt, StreamExt};
+    use std::{cell::Cell, rc::Rc, thread};
+
+    #[test]
+    fn shutdown_drains_every_accepted_item_across_threads() {
+        for _ in 0..20 {
+            let (tx, mut rx) = Pipeline::bounded(8);
+            let shutdown = rx.shutdown_handle();
+
+            let producers = (0..4)
+                .map(|_| {
+                    let mut tx = tx.clone();
+                    thread::spawn(move || {
+                        block_on(async {
+                            let mut sent = 0usize;
+                            loop {
+                                match tx.send(sent).await {
+                                    Ok(()) => sent += 1,
+                                    Err(error) => {
+                                        assert_eq!(error, PipelineError::Shutdown(sent));
+                                        return sent;
+                                    }
+                                }
+                            }
+                        })
+                    })
+                })
+                .collect::<Vec<_>>();
+            drop(tx);
+
+            let received = block_on(async {
+                let mut received = 0usize;
+                let mut drained = None;
+                while rx.next().await.is_some() {
+                    received += 1;
+                    if received == 100 {
+                        drained = Some(shutdown.shutdown());
+                    }
+                }
+                assert_eq!(drained.unwrap().await, received);
+                received
+            });
+
+            let sent: usize = producers.into_iter().map(|h| h.join().unwrap()).sum();
+            assert_eq!(sent, received);
+        }
+    }
+
+    #[test]
+    fn dropping_receiver_resolves_shutdown() {
+        let (mut tx, rx) = Pipeline::bounded(8);
+        let shutdown = rx.shutdown_handle();
+        block_on(tx.send(1)).unwrap();
+        drop(rx);
+
+        assert_eq!(block_on(shutdown.shutdown()), 0);
+        assert_eq!(block_on(tx.send(2)), Err(PipelineError::Closed(2)));
+    }
+
+    /// Sends `count` items one `Sink` call at a time, yielding between calls, until shutdown
+    /// rejects one. Accepted items are counted in `accepted`.
+    async fn produce(mut tx: Pipeline<usize>, count: usize, accepted: Rc<Cell<usize>>) {
+        for item in 0..count {
+            poll_fn(|cx| tx.poll_ready_unpin(cx)).await.unwrap();
+            yield_now().await;
+            match tx.start_send_unpin(item) {
+                Ok(()) => accepted.set(accepted.get() + 1),
+                Err(PipelineError::Shutdown(_)) => return,
+                Err(error) => panic!("unexpected error: {}", error),
+            }
+            yield_now().await;
+        }
+    }
+
+    #[test]
+    fn shutdown_drains_every_accepted_item_in_every_interleaving() {
+        let runs = Model::new().preemption_bound(3).check(|spawner| {
+            let (tx, mut rx) = Pipeline::bounded(1);
+            let shutdown = rx.shutdown_handle();
+            let accepted = Rc::new(Cell::new(0));
+            let received = Rc::new(Cell::new(0));
+            let drained = Rc::new(Cell::new(None));
+
+            spawner.spawn(produce(tx.clone(), 2, Rc::clone(&accepted)));
+            spawner.spawn(produce(tx, 2, Rc::clone(&accepted)));
+            spawner.spawn({
+                let received = Rc::clone(&received);
+                async move {
+                    while rx.next().await.is_some() {
+                        received.set(received.get() + 1);
+                        yield_now().await;
+                    }
+                }
+            });
+            spawner.spawn({
+                let drained = Rc::clone(&drained);
+                async move { drained.set(Some(shutdown.shutdown().await)) }
+            });
+
+            move || {
+                assert_eq!(accepted.get(), received.get(), "accepted items were lost");
+                assert_eq!(drained.get(), Some(received.get()));
+            }
+        });
+        assert!(runs > 1000, "only {} interleavings explored", runs);
+    }
+
+    /// The protocol this module replaces: shutdown closes the channel straight away, so a
+    /// producer whose `poll_ready` already succeeded has its item refused by `start_send`, after
+    /// `forward` has taken it from the stream.
+    #[test]
+    #[should_panic(expected = "accepted items were lost")]
+    fn closing_immediately_loses_items() {
+        Model::new().preemption_bound(2).check(|spawner| {
+            let (mut tx, mut rx) = futures::channel::mpsc::channel::<usize>(1);
+            let lost = Rc::new(Cell::new(0));
+
+            spawner.spawn({
+                let lost = Rc::clone(&lost);
+                async move {
+                    for item in 0..2 {
+                        if poll_fn(|cx| tx.poll_ready_unpin(cx)).await.is_err() {
+                            return;
+                        }
+                        yield_now().await;
+                        if tx.start_send_unpin(item).is_err() {
+                            lost.set(lost.get() + 1);
+                        }
+                        yield_now().await;
+                    }
+                }
+            });
+            spawner.spawn(async move {
+                rx.close();
+                while rx.next().await.is_some() {}
+            });
+
+            move || assert_eq!(lost.get(), 0, "accepted items were lost")
+        });
+    }
+}
@@ -0,0 +1,476 @@
+use crate::{Pipeline, PipelineError, PipelineReceiver, ShutdownCoordinator, ShutdownReport};
+use futures::{
+    channel::oneshot,
+    future::{self, BoxFuture},
+    stream, FutureExt, Sink, Stream, StreamExt,
+};
+use std::{
+    collections::HashSet,
+    error, fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::task::JoinHandle;
+
+const DEFAULT_CAPACITY: usize = 128;
+
+/// Error returned while assembling or running a `Topology`.
+#[derive(Debug)]
+pub enum TopologyError {
+    /// Two stages were given the same name.
+    DuplicateName(String),
+    /// A stage's output was connected to more than one downstream stage.
+    AlreadyConnected(String),
+    /// A source or transform has nothing downstream to send its output to.
+    Unconnected(String),
+    /// A sink returned an error, after which its stage stopped.
+    Failed { stage: String, message: String },
+    /// A stage's task panicked.
+    Panicked(String),
+}
+
+impl fmt::Display for TopologyError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TopologyError::DuplicateName(stage) => write!(fmt, "duplicate stage name {:?}", stage),
+            TopologyError::AlreadyConnected(stage) => {
+                write!(fmt, "output of stage {:?} is already connected", stage)
+            }
+            TopologyError::Unconnected(stage) => {
+                write!(fmt, "output of stage {:?} is not connected", stage)
+            }
+            TopologyError::Failed { stage, message } => {
+                write!(fmt, "stage {:?} failed: {}", stage, message)
+            }
+            TopologyError::Panicked(stage) => write!(fmt, "stage {:?} panicked", stage),
+        }
+    }
+}
+
+impl error::Error for TopologyError {}
+
+/// The output of a source or transform, to be connected to exactly one downstream stage.
+pub struct Port<T> {
+    stage: String,
+    output: Arc<Mutex<Option<Pipeline<T>>>>,
+}
+
+impl<T> Port<T> {
+    pub fn stage(&self) -> &str {
+        &self.stage
+    }
+}
+
+impl<T> fmt::Debug for Port<T> {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Port")
+            .field("stage", &self.stage)
+            .finish()
+    }
+}
+
+type StageTask = BoxFuture<'static, Result<(), TopologyError>>;
+
+/// A stage waiting to be spawned, which fails if its output was never connected.
+struct Stage {
+    name: String,
+    start: Box<dyn FnOnce() -> Result<StageTask, TopologyError> + Send>,
+}
+
+/// Builds a graph of named sources, transforms and sinks joined by `Pipeline`s.
+///
+/// Each connection between stages gets its own channel of `capacity` items, and each stage runs
+/// as its own task once `spawn` is called.
+pub struct TopologyBuilder {
+    capacity: usize,
+    names: HashSet<String>,
+    stages: Vec<Stage>,
+    stops: Vec<oneshot::Sender<()>>,
+    /// Resolve once the matching source has finished and dropped its output.
+    sources: Vec<oneshot::Receiver<()>>,
+    coordinator: ShutdownCoordinator,
+    error: Option<TopologyError>,
+}
+
+impl TopologyBuilder {
+    pub fn new() -> Self {
+        Self {
+            capacity: DEFAULT_CAPACITY,
+            names: HashSet::new(),
+            stages: Vec::new(),
+            stops: Vec::new(),
+            sources: Vec::new(),
+            coordinator: ShutdownCoordinator::new(),
+            error: None,
+        }
+    }
+
+    /// Capacity of the channels created for connections added from here on.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Adds a stage that feeds the items of `source` downstream until it ends or the topology
+    /// is shut down.
+    pub fn source<T, S>(&mut self, name: &str, source: S) -> Port<T>
+    where
+        T: Send + 'static,
+        S: Stream<Item = T> + Send + 'static,
+    {
+        let (stop_tx, stop_rx) = oneshot::channel();
+        self.stops.push(stop_tx);
+        // A dropped `Topology` never asks to stop, so only an explicit shutdown ends the source.
+        let stop = stop_rx.then(|result| match result {
+            Ok(()) => future::ready(()).left_future(),
+            Err(_) => future::pending().right_future(),
+        });
+        let source = source.take_until(stop);
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+        self.sources.push(done_rx);
+        self.add_producer(name, move |output| {
+            forward(source, output).inspect(move |_| drop(done_tx))
+        })
+    }
+
+    /// Adds a stage that maps every item of `input` to any number of output items.
+    pub fn transform<T, U, F>(&mut self, name: &str, input: &Port<T>, mut f: F) -> Port<U>
+    where
+        T: Send + 'static,
+        U: Send + 'static,
+        F: FnMut(T) -> Vec<U> + Send + 'static,
+    {
+        let input = self.connect(name, input);
+        self.add_producer(name, move |output| {
+            forward(input.flat_map(move |item| stream::iter(f(item))), output)
+        })
+    }
+
+    /// Like `transform`, for a function that needs to wait on something.
+    pub fn transform_async<T, U, F, Fut>(&mut self, name: &str, input: &Port<T>, f: F) -> Port<U>
+    where
+        T: Send + 'static,
+        U: Send + 'static,
+        F: FnMut(T) -> Fut + Send + 'static,
+        Fut: Future<Output = Vec<U>> + Send + 'static,
+    {
+        let input = self.connect(name, input);
+        self.add_producer(name, move |output| {
+            forward(input.then(f).flat_map(stream::iter), output)
+        })
+    }
+
+    /// Adds a stage that sends every item of `input` to `sink`, closing it once `input` ends.
+    pub fn sink<T, S>(&mut self, name: &str, input: &Port<T>, sink: S)
+    where
+        T: Send + 'static,
+        S: Sink<T> + Send + Unpin + 'static,
+        S::Error: fmt::Display,
+    {
+        let input = self.connect(name, input);
+        let stage = name.to_string();
+        self.add_stage(name, move || {
+            let task = async move {
+                input
+                    .map(Ok)
+                    .forward(sink)
+                    .await
+                    .map_err(|error| TopologyError::Failed {
+                        stage,
+                        message: error.to_string(),
+                    })
+            };
+            Ok(task.boxed())
+        });
+    }
+
+    /// Spawns a task for every stage onto the current tokio runtime.
+    pub fn spawn(self) -> Result<Topology, TopologyError> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+        let tasks = self
+            .stages
+            .into_iter()
+            .map(|stage| Ok((stage.name, (stage.start)()?)))
+            .collect::<Result<Vec<_>, TopologyError>>()?;
+        let stages = tasks
+            .into_iter()
+            .map(|(name, task)| (name, tokio::spawn(task)))
+            .collect();
+        Ok(Topology {
+            stages,
+            stops: self.stops,
+            sources: self.sources,
+            coordinator: self.coordinator,
+        })
+    }
+
+    /// Creates the channel feeding the stage `name` from `input`.
+    fn connect<T>(&mut self, name: &str, input: &Port<T>) -> PipelineReceiver<T> {
+        let (tx, rx) = Pipeline::bounded(self.capacity);
+        // Sources have no channel of their own, so a stage fed by one has nothing upstream.
+        if !self.names.contains(name) {
+            let upstream = Some(input.stage.as_str()).filter(|s| self.coordinator.has_stage(s));
+            self.coordinator
+                .add_stage(name, rx.shutdown_handle(), upstream.as_slice());
+        }
+        let mut output = input.output.lock().unwrap();
+        if output.is_some() {
+            self.fail(TopologyError::AlreadyConnected(input.stage.clone()));
+        } else {
+            *output = Some(tx);
+        }
+        rx
+    }
+
+    fn add_producer<T, F, Fut>(&mut self, name: &str, run: F) -> Port<T>
+    where
+        F: FnOnce(Pipeline<T>) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), PipelineError<T>>> + Send + 'static,
+        T: Send + 'static,
+    {
+        let output = Arc::new(Mutex::new(None));
+        let port = Port {
+            stage: name.to_string(),
+            output: Arc::clone(&output),
+        };
+        let stage = name.to_string();
+        self.add_stage(name, move || match output.lock().unwrap().take() {
+            Some(output) => Ok(run(output)
+                .map(move |result| match result {
+                    // The stage downstream went away, which it reports itself.
+                    Ok(()) | Err(PipelineError::Closed(_)) => Ok(()),
+                    Err(error) => Err(TopologyError::Failed {
+                        stage,
+                        message: format!("item refused downstream: {}", error),
+                    }),
+                })
+                .boxed()),
+            None => Err(TopologyError::Unconnected(stage)),
+        });
+        port
+    }
+
+    fn add_stage<F>(&mut self, name: &str, start: F)
+    where
+        F: FnOnce() -> Result<StageTask, TopologyError> + Send + 'static,
+    {
+        if !self.names.insert(name.to_string()) {
+            self.fail(TopologyError::DuplicateName(name.to_string()));
+        }
+        self.stages.push(Stage {
+            name: name.to_string(),
+            start: Box::new(start),
+        });
+    }
+
+    /// Keeps the first error, to be returned by `spawn`.
+    fn fail(&mut self, error: TopologyError) {
+        self.error.get_or_insert(error);
+    }
+}
+
+impl Default for TopologyBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for TopologyBuilder {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("TopologyBuilder")
+            .field("capacity", &self.capacity)
+            .field(
+                "stages",
+                &self.stages.iter().map(|s| &s.name).collect::<Vec<_>>(),
+            )
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+/// Sends everything from `input` into `output`, stopping at the first item `output` refuses and
+/// handing it back.
+async fn forward<T, S>(input: S, output: Pipeline<T>) -> Result<(), PipelineError<T>>
+where
+    T: Send + 'static,
+    S: Stream<Item = T> + Send,
+{
+    input.map(Ok).forward(output).await
+}
+
+/// A running topology, returned by `TopologyBuilder::spawn`.
+pub struct Topology {
+    stages: Vec<(String, JoinHandle<Result<(), TopologyError>>)>,
+    stops: Vec<oneshot::Sender<()>>,
+    sources: Vec<oneshot::Receiver<()>>,
+    coordinator: ShutdownCoordinator,
+}
+
+impl Topology {
+    pub fn builder() -> TopologyBuilder {
+        TopologyBuilder::new()
+    }
+
+    pub fn stages(&self) -> impl Iterator<Item = &str> {
+        self.stages.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Stops every source from taking further items. Everything they have already produced
+    /// flows on through the transforms to the sinks, after which every stage finishes.
+    pub fn shutdown(&mut self) {
+        for stop in self.stops.drain(..) {
+            let _ = stop.send(());
+        }
+    }
+
+    /// Like `shutdown`, then waits for each stage to drain in turn, force stopping whatever is
+    /// left once `deadline` has passed. Stages reading from a source are reported under their
+    /// own names, in the order they were added.
+    ///
+    /// The channels fed by sources are only shut down once the sources have finished, so an
+    /// item a source was in the middle of sending isn't refused.
+    pub async fn shutdown_within(&mut self, deadline: Duration) -> ShutdownReport {
+        let started = Instant::now();
+        self.shutdown();
+        let sources = future::join_all(self.sources.drain(..));
+        let _ = tokio::time::timeout(deadline, sources).await;
+        self.coordinator
+            .shutdown(deadline.saturating_sub(started.elapsed()))
+            .await
+    }
+
+    /// Waits for every stage to finish, returning the first failure.
+    pub async fn wait(self) -> Result<(), TopologyError> {
+        let mut result = Ok(());
+        for (name, handle) in self.stages {
+            let stage_result = match handle.await {
+                Ok(stage_result) => stage_result,
+                Err(_) => Err(TopologyError::Panicked(name)),
+            };
+            if result.is_ok() {
+                result = stage_result;
+            }
+        }
+        result
+    }
+}
+
+impl fmt::Debug for Topology {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt.debug_struct("Topology")
+            .field("stages", &self.stages().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Topology, TopologyError};
+    use crate::Pipeline;
+    use futures::{stream, StreamExt};
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn runs_stages_to_completion() {
+        let (out_tx, out_rx) = Pipeline::bounded(100);
+        let mut builder = Topology::builder().capacity(4);
+        let numbers = builder.source("numbers", stream::iter(0..10u32));
+        let evens = builder.transform("evens", &numbers, |n| {
+            if n % 2 == 0 {
+                vec![n, n]
+            } else {
+                vec![]
+            }
+        });
+        let scaled = builder.transform_async("scaled", &evens, |n| async move { vec![n * 10] });
+        builder.sink("out", &scaled, out_tx);
+
+        let topology = builder.spawn().unwrap();
+        assert_eq!(
+            topology.stages().collect::<Vec<_>>(),
+            ["numbers", "evens", "scaled", "out"]
+        );
+        topology.wait().await.unwrap();
+
+        let items = out_rx.collect::<Vec<_>>().await;
+        assert_eq!(items, [0, 0, 20, 20, 40, 40, 60, 60, 80, 80]);
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_everything_produced() {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let (out_tx, out_rx) = Pipeline::bounded(10_000);
+        let mut builder = Topology::builder().capacity(8);
+        let source = builder.source("repeat", {
+            let produced = Arc::clone(&produced);
+            stream::repeat(1u8).inspect(move |_| {
+                produced.fetch_add(1, Ordering::SeqCst);
+            })
+        });
+        let copied = builder.transform("copy", &source, |item| vec![item]);
+        builder.sink("out", &copied, out_tx);
+
+        let mut topology = builder.spawn().unwrap();
+        tokio::time::delay_for(Duration::from_millis(5)).await;
+        topology.shutdown();
+        topology.wait().await.unwrap();
+
+        let received = out_rx.count().await;
+        assert!(received > 0);
+        assert_eq!(received, produced.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn shutdown_within_reports_each_stage() {
+        let produced = Arc::new(AtomicUsize::new(0));
+        let (out_tx, out_rx) = Pipeline::bounded(10_000);
+        let mut builder = Topology::builder().capacity(8);
+        let source = builder.source("repeat", {
+            let produced = Arc::clone(&produced);
+            stream::repeat(1u8).inspect(move |_| {
+                produced.fetch_add(1, Ordering::SeqCst);
+            })
+        });
+        let copied = builder.transform("copy", &source, |item| vec![item]);
+        builder.sink("out", &copied, out_tx);
+
+        let mut topology = builder.spawn().unwrap();
+        tokio::time::delay_for(Duration::from_millis(5)).await;
+        let report = topology.shutdown_within(Duration::from_secs(5)).await;
+        topology.wait().await.unwrap();
+
+        assert!(report.is_clean());
+        let names = report
+            .stages
+            .iter()
+            .map(|s| s.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, ["copy", "out"]);
+        let received = out_rx.count().await;
+        assert_eq!(report.stages[1].delivered, received);
+        assert_eq!(received, produced.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn rejects_invalid_graphs() {
+        let mut builder = Topology::builder();
+        builder.source("a", stream::iter(vec![1]));
+        builder.source("a", stream::iter(vec![2]));
+        assert!(matches!(builder.spawn(), Err(TopologyError::DuplicateName(name)) if name == "a"));
+
+        let mut builder = Topology::builder();
+        builder.source("dangling", stream::iter(vec![1]));
+        assert!(
+            matches!(builder.spawn(), Err(TopologyError::Unconnected(name)) if name == "dangling")
+        );
+    }
+}